@@ -0,0 +1,538 @@
+//! Two pass assembler for the CEC470 instruction set.
+//!
+//! Each line holds an optional `label:`, then an optional instruction or directive, then an
+//! optional `;` comment. Operands are written as:
+//!   `ACC`, `MAR`      registers
+//!   `[MAR]`           the byte (or word when MAR is the destination) that MAR points to
+//!   `#value`          an immediate constant
+//!   `[address]`       the memory at a fixed address
+//! where values and addresses are labels or hex (`0x1F`), binary (`0b101`) or decimal numbers.
+//!
+//! Math instructions are written destination first, `ADD ACC, #1`. `INC`, `DEC` and `NOT` only
+//! need a destination. Loads and stores are written register first, `LOAD MAR, #0x2000` and
+//! `STORE ACC, [MAR]`. Branches take a target address, `BNE loop`.
+//!
+//! The directives are `.org address`, which moves where following bytes are placed, and
+//! `.byte` and `.word`, which place comma separated 8 and 16 bit values
+
+use crate::{
+    encode, operand_widths, BranchKind, DstTarget, Instruction, MathFunction, MemoryMethod,
+    Register, SrcTarget, MEMORY_SIZE,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AssembleError {
+    /// 1 based line number
+    pub line: usize,
+    /// The offending line, as written
+    pub source: String,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: {}\n    {}",
+            self.line,
+            self.message,
+            self.source.trim()
+        )
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// A number, or a label standing in for one
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Value {
+    Number(i64),
+    Label(String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Operand {
+    Acc,
+    Mar,
+    /// `[MAR]`
+    Indirect,
+    /// `#value`
+    Constant(Value),
+    /// `[address]`
+    Memory(Value),
+    /// A bare value, used for branch targets
+    Value(Value),
+}
+
+/// A parsed line that places bytes in memory
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Statement {
+    /// An instruction and its operand values, in the order the cpu fetches them
+    Instruction(Instruction, Vec<Value>),
+    Bytes(Vec<Value>),
+    Words(Vec<Value>),
+}
+
+impl Statement {
+    /// Number of bytes the statement assembles to. This never depends on the value of a label,
+    /// so addresses can be assigned before labels are resolved
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction(instruction, _) => {
+                1 + operand_widths(instruction).iter().sum::<usize>()
+            }
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => values.len() * 2,
+        }
+    }
+}
+
+/// A statement along with where it came from and where it goes
+struct Placed {
+    line: usize,
+    addr: usize,
+    statement: Statement,
+}
+
+/// Assembles `source` into a memory image. Bytes not written by the program are zero
+pub fn assemble(source: &str) -> Result<[u8; MEMORY_SIZE], AssembleError> {
+    let lines: Vec<&str> = source.lines().collect();
+    let error = |line: usize, message: String| AssembleError {
+        line: line + 1,
+        source: lines[line].to_owned(),
+        message,
+    };
+
+    // First pass: parse every line, assigning addresses to statements and labels
+    let mut labels = HashMap::new();
+    let mut placed = vec![];
+    let mut addr = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let mut text = line.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                return Err(error(i, format!("invalid label `{label}`")));
+            }
+            if labels.insert(label.to_owned(), addr).is_some() {
+                return Err(error(i, format!("label `{label}` is already defined")));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+            None => (text, ""),
+        };
+        let operands: Vec<&str> = match operands {
+            "" => vec![],
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        if mnemonic.eq_ignore_ascii_case(".org") {
+            let [operand] = operands[..] else {
+                return Err(error(i, "`.org` takes one address".to_owned()));
+            };
+            addr = match parse_value(operand).map_err(|message| error(i, message))? {
+                Value::Number(n) if (0..MEMORY_SIZE as i64).contains(&n) => n as usize,
+                Value::Number(n) => return Err(error(i, format!("address {n} is out of range"))),
+                Value::Label(_) => {
+                    return Err(error(i, "`.org` cannot use a label".to_owned()));
+                }
+            };
+            continue;
+        }
+        let statement =
+            parse_statement(mnemonic, &operands).map_err(|message| error(i, message))?;
+        let size = statement.size();
+        if addr + size > MEMORY_SIZE {
+            return Err(error(i, "runs past the end of memory".to_owned()));
+        }
+        placed.push(Placed {
+            line: i,
+            addr,
+            statement,
+        });
+        addr += size;
+    }
+
+    // Second pass: resolve labels and emit bytes
+    let mut memory = [0; MEMORY_SIZE];
+    let mut written = vec![false; MEMORY_SIZE];
+    for placed in placed {
+        let bytes = encode_statement(&placed.statement, &labels)
+            .map_err(|message| error(placed.line, message))?;
+        let range = placed.addr..placed.addr + bytes.len();
+        if written[range.clone()].iter().any(|&written| written) {
+            return Err(error(
+                placed.line,
+                format!("overlaps bytes already placed at 0x{:04X}", placed.addr),
+            ));
+        }
+        written[range.clone()].fill(true);
+        memory[range].copy_from_slice(&bytes);
+    }
+    Ok(memory)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2)
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse()
+    } else if !negative && is_identifier(text) {
+        return Ok(Value::Label(text.to_owned()));
+    } else {
+        return Err(format!("expected a number or label, found `{text}`"));
+    };
+    match parsed {
+        Ok(n) if negative => Ok(Value::Number(-n)),
+        Ok(n) => Ok(Value::Number(n)),
+        Err(_) => Err(format!("invalid number `{text}`")),
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if text.eq_ignore_ascii_case("acc") {
+        Ok(Operand::Acc)
+    } else if text.eq_ignore_ascii_case("mar") {
+        Ok(Operand::Mar)
+    } else if let Some(value) = text.strip_prefix('#') {
+        Ok(Operand::Constant(parse_value(value.trim())?))
+    } else if let Some(inner) = text.strip_prefix('[') {
+        let inner = inner
+            .strip_suffix(']')
+            .ok_or_else(|| format!("missing `]` in `{text}`"))?
+            .trim();
+        if inner.eq_ignore_ascii_case("mar") {
+            Ok(Operand::Indirect)
+        } else {
+            Ok(Operand::Memory(parse_value(inner)?))
+        }
+    } else {
+        Ok(Operand::Value(parse_value(text)?))
+    }
+}
+
+fn parse_statement(mnemonic: &str, operands: &[&str]) -> Result<Statement, String> {
+    let upper = mnemonic.to_ascii_uppercase();
+    let operands = operands
+        .iter()
+        .map(|operand| parse_operand(operand))
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = |expected: usize| match operands.len() == expected {
+        true => Ok(()),
+        false => Err(format!(
+            "`{mnemonic}` takes {expected} operand{}, found {}",
+            if expected == 1 { "" } else { "s" },
+            operands.len()
+        )),
+    };
+    let register = |operand: &Operand| match operand {
+        Operand::Acc => Ok(Register::Acc),
+        Operand::Mar => Ok(Register::Mar),
+        _ => Err(format!("`{mnemonic}` needs ACC or MAR as its register")),
+    };
+    let values = |operands: Vec<Operand>| {
+        operands
+            .into_iter()
+            .map(|operand| match operand {
+                Operand::Value(value) => Ok(value),
+                _ => Err(format!("`{mnemonic}` only takes plain values")),
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+
+    let func = match upper.as_str() {
+        "AND" => Some(MathFunction::And),
+        "OR" => Some(MathFunction::Or),
+        "XOR" => Some(MathFunction::Xor),
+        "ADD" => Some(MathFunction::Add),
+        "SUB" => Some(MathFunction::Sub),
+        "INC" => Some(MathFunction::Inc),
+        "DEC" => Some(MathFunction::Dec),
+        "NOT" => Some(MathFunction::Not),
+        _ => None,
+    };
+    if let Some(func) = func {
+        let unary = matches!(
+            func,
+            MathFunction::Inc | MathFunction::Dec | MathFunction::Not
+        );
+        let mut operands = operands.into_iter();
+        let (dst, src) = match (operands.next(), operands.next(), operands.next()) {
+            // The source of a unary op is ignored, so default to one that fetches nothing
+            (Some(dst), None, None) if unary => (dst, Operand::Acc),
+            (Some(dst), Some(src), None) => (dst, src),
+            _ => {
+                return Err(format!(
+                    "`{mnemonic}` takes a destination{}",
+                    if unary {
+                        " and an optional source"
+                    } else {
+                        " and a source"
+                    }
+                ))
+            }
+        };
+        // The source operand is fetched before the destination address
+        let mut values = vec![];
+        let src = match src {
+            Operand::Indirect => SrcTarget::Indirect,
+            Operand::Acc => SrcTarget::Acc,
+            Operand::Constant(value) => {
+                values.push(value);
+                SrcTarget::Constant
+            }
+            Operand::Memory(addr) => {
+                values.push(addr);
+                SrcTarget::Memory
+            }
+            Operand::Mar | Operand::Value(_) => {
+                return Err(format!(
+                    "`{mnemonic}` source must be ACC, [MAR], #constant or [address]"
+                ))
+            }
+        };
+        let dst = match dst {
+            Operand::Indirect => DstTarget::Indirect,
+            Operand::Acc => DstTarget::Acc,
+            Operand::Mar => DstTarget::Mar,
+            Operand::Memory(addr) => {
+                values.push(addr);
+                DstTarget::Memory
+            }
+            Operand::Constant(_) | Operand::Value(_) => {
+                return Err(format!("`{mnemonic}` cannot write to a constant"))
+            }
+        };
+        return Ok(Statement::Instruction(
+            Instruction::Mathmatical { func, src, dst },
+            values,
+        ));
+    }
+
+    let kind = match upper.as_str() {
+        "BRA" => Some(BranchKind::Bra),
+        "BRZ" => Some(BranchKind::Brz),
+        "BNE" => Some(BranchKind::Bne),
+        "BLT" => Some(BranchKind::Blt),
+        "BLE" => Some(BranchKind::Ble),
+        "BGT" => Some(BranchKind::Bgt),
+        "BGE" => Some(BranchKind::Bge),
+        _ => None,
+    };
+    if let Some(kind) = kind {
+        count(1)?;
+        return match operands.into_iter().next().unwrap() {
+            Operand::Value(target) => Ok(Statement::Instruction(
+                Instruction::Branch(kind),
+                vec![target],
+            )),
+            _ => Err(format!("`{mnemonic}` takes a target address")),
+        };
+    }
+
+    match upper.as_str() {
+        "LOAD" | "STORE" => {
+            count(2)?;
+            let reg = register(&operands[0])?;
+            let (method, values) = match operands[1].clone() {
+                Operand::Memory(addr) => (MemoryMethod::Address, vec![addr]),
+                Operand::Constant(value) => (MemoryMethod::Constant, vec![value]),
+                Operand::Indirect => (MemoryMethod::Indirect, vec![]),
+                _ => {
+                    return Err(format!(
+                        "`{mnemonic}` needs #constant, [address] or [MAR] as its second operand"
+                    ))
+                }
+            };
+            let instruction = match upper.as_str() {
+                "LOAD" => Instruction::Load {
+                    dst: reg,
+                    src: method,
+                },
+                _ => Instruction::Store {
+                    src: reg,
+                    dst: method,
+                },
+            };
+            Ok(Statement::Instruction(instruction, values))
+        }
+        "NOP" => count(0).map(|_| Statement::Instruction(Instruction::Nop, vec![])),
+        "HALT" => count(0).map(|_| Statement::Instruction(Instruction::Hault, vec![])),
+        ".BYTE" if !operands.is_empty() => Ok(Statement::Bytes(values(operands)?)),
+        ".WORD" if !operands.is_empty() => Ok(Statement::Words(values(operands)?)),
+        ".BYTE" | ".WORD" => Err(format!("`{mnemonic}` needs at least one value")),
+        _ => Err(format!("unknown instruction `{mnemonic}`")),
+    }
+}
+
+/// Looks up `value`, checking it fits in `bits` bits. Negative numbers are stored as two's
+/// complement
+fn resolve(value: &Value, bits: u32, labels: &HashMap<String, usize>) -> Result<u16, String> {
+    let n = match value {
+        Value::Number(n) => *n,
+        Value::Label(label) => *labels
+            .get(label)
+            .ok_or_else(|| format!("undefined label `{label}`"))?
+            as i64,
+    };
+    let min = -(1 << (bits - 1));
+    let max = (1 << bits) - 1;
+    if n < min || n > max {
+        return Err(format!("{n} does not fit in {bits} bits"));
+    }
+    Ok((n as u16) & max as u16)
+}
+
+fn encode_statement(
+    statement: &Statement,
+    labels: &HashMap<String, usize>,
+) -> Result<Vec<u8>, String> {
+    match statement {
+        Statement::Instruction(instruction, values) => {
+            let operands = operand_widths(instruction)
+                .iter()
+                .zip(values)
+                .map(|(&width, value)| resolve(value, width as u32 * 8, labels))
+                .collect::<Result<Vec<_>, _>>()?;
+            encode(instruction, &operands).map_err(|err| err.to_string())
+        }
+        Statement::Bytes(values) => values
+            .iter()
+            .map(|value| resolve(value, 8, labels).map(|n| n as u8))
+            .collect(),
+        Statement::Words(values) => {
+            let mut bytes = vec![];
+            for value in values {
+                bytes.extend(resolve(value, 16, labels)?.to_be_bytes());
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn assembles_mem_in_prefix() {
+        let memory = assemble(
+            "
+            LOAD MAR, [0x1000]
+            STORE MAR, [0x2000]
+            LOAD ACC, #0x1A
+            STORE ACC, [0x2002]
+            LOAD MAR, #0x2006
+            STORE MAR, [0x2004]
+            LOAD ACC, [0x1000]
+            INC ACC
+            ",
+        )
+        .unwrap();
+        let expected = include!("../mem_in.txt");
+        assert_eq!(memory[..0x15], expected[..0x15]);
+    }
+
+    #[test]
+    fn resolves_labels_and_directives() {
+        let memory = assemble(
+            "
+                    LOAD ACC, [count]   ; forward reference
+            loop:   DEC ACC
+                    BNE loop
+                    STORE ACC, [result]
+                    HALT
+                    .org 0x2000
+            count:  .byte 3
+            result: .byte 0xFF, -1, 0b1010
+                    .word loop, 0x1234
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            memory[..0x0C],
+            [0x08, 0x20, 0x00, 0xE5, 0x12, 0x00, 0x03, 0x00, 0x20, 0x01, 0x19, 0x00]
+        );
+        assert_eq!(
+            memory[0x2000..0x2009],
+            [0x03, 0xFF, 0xFF, 0x0A, 0x00, 0x03, 0x12, 0x34, 0x00]
+        );
+
+        let mut computer = Computer::new(memory);
+        computer.run().unwrap();
+        assert_eq!(computer.memory()[0x2001], 0x00);
+        assert_eq!(computer.timing().instructions, 1 + 3 * 2 + 2);
+    }
+
+    #[test]
+    fn encodes_operand_widths() {
+        let memory = assemble(
+            "
+            ADD MAR, #0x1234
+            ADD ACC, #0x12
+            SUB [0x2000], [0x3000]
+            NOT [MAR]
+            XOR ACC, [MAR]
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            memory[..12],
+            [0xBA, 0x12, 0x34, 0xB6, 0x12, 0xCF, 0x30, 0x00, 0x20, 0x00, 0xF1, 0xA4]
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = |source| assemble(source).unwrap_err();
+
+        let err = error("NOP\n  FOO ACC ; typo");
+        assert_eq!(err.line, 2);
+        assert_eq!(
+            err.to_string(),
+            "line 2: unknown instruction `FOO`\n    FOO ACC ; typo"
+        );
+
+        assert_eq!(error("BRA nowhere").message, "undefined label `nowhere`");
+        assert_eq!(
+            error("LOAD ACC, #256").message,
+            "256 does not fit in 8 bits"
+        );
+        assert_eq!(
+            error("a: NOP\na: NOP").message,
+            "label `a` is already defined"
+        );
+        assert_eq!(
+            error("ADD #1, ACC").message,
+            "`ADD` cannot write to a constant"
+        );
+        assert_eq!(
+            error(".org 0x10\nNOP\n.org 0x10\nHALT").message,
+            "overlaps bytes already placed at 0x0010"
+        );
+        assert_eq!(
+            error(".org 0xFFFF\nBRA 0").message,
+            "runs past the end of memory"
+        );
+    }
+}
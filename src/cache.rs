@@ -0,0 +1,835 @@
+//! Set associative cache model that sits between the cpu and main memory
+
+use crate::snapshot::{invalid, SnapshotReader, SnapshotWriter};
+use crate::{
+    MissBreakdown, MissClassifier, MissKind, ReplacementKind, ReplacementPolicy, MEMORY_SIZE,
+};
+use std::io;
+use std::str::FromStr;
+
+/// The geometry of a cache
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CacheConfig {
+    /// Number of bytes in each line. Must be a power of two
+    pub line_size: usize,
+    /// Number of sets. Must be a power of two
+    pub sets: usize,
+    /// Number of lines in each set
+    pub ways: usize,
+    /// Which policy picks the line to evict when a set is full
+    pub replacement: ReplacementKind,
+    /// When stores that hit reach the next level of memory
+    pub write_policy: WritePolicy,
+    /// What happens when a store misses
+    pub write_miss: WriteMissPolicy,
+    /// Cycles it takes to look up a line in this cache
+    pub latency: u64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum WritePolicy {
+    /// Every store is immediately written to memory as well as to the cache
+    WriteThrough,
+    /// Stores only update the cache and mark the line dirty. Dirty lines are written to memory
+    /// when they are evicted or the cache is flushed
+    #[default]
+    WriteBack,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum WriteMissPolicy {
+    /// The missing line is brought into the cache before it is written
+    #[default]
+    WriteAllocate,
+    /// The store goes straight to memory without bringing the line into the cache
+    NoWriteAllocate,
+}
+
+impl CacheConfig {
+    /// Creates a write back, write allocate config using lru replacement and a one cycle
+    /// latency
+    pub fn new(line_size: usize, sets: usize, ways: usize) -> Self {
+        Self {
+            line_size,
+            sets,
+            ways,
+            replacement: ReplacementKind::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_miss: WriteMissPolicy::WriteAllocate,
+            latency: 1,
+        }
+    }
+
+    pub fn with_replacement(self, replacement: ReplacementKind) -> Self {
+        Self {
+            replacement,
+            ..self
+        }
+    }
+
+    pub fn with_write_policy(self, write_policy: WritePolicy, write_miss: WriteMissPolicy) -> Self {
+        Self {
+            write_policy,
+            write_miss,
+            ..self
+        }
+    }
+
+    pub fn with_latency(self, latency: u64) -> Self {
+        Self { latency, ..self }
+    }
+
+    /// Total number of data bytes the cache can hold
+    pub fn size(&self) -> usize {
+        self.line_size * self.sets * self.ways
+    }
+}
+
+impl Default for CacheConfig {
+    /// A 512 byte, 2 way, write back lru cache with 16 byte lines
+    fn default() -> Self {
+        Self::new(16, 16, 2)
+    }
+}
+
+/// Parses `<line size>x<sets>x<ways>` followed by any of these options, separated by commas:
+/// a replacement policy such as `fifo` (see [`ReplacementKind`]'s `FromStr` impl),
+/// `write-through`, `write-back`, `write-allocate`, `no-write-allocate` and `latency=<cycles>`.
+/// So `16x16x2` is the default cache, and `4x64x1,write-through,latency=2` a direct mapped write
+/// through cache with 4 byte lines and a two cycle latency. Configs [`Cache::new`] would reject
+/// are errors
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parts = text.split(',');
+        let geometry = parts.next().unwrap();
+        let numbers = geometry
+            .split('x')
+            .map(|number| number.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>();
+        let &[line_size, sets, ways] = numbers.as_deref().unwrap_or_default() else {
+            return Err(format!(
+                "cache geometry `{geometry}` is not <line size>x<sets>x<ways>"
+            ));
+        };
+        let mut config = CacheConfig::new(line_size, sets, ways);
+        for option in parts {
+            match option {
+                "write-through" => config.write_policy = WritePolicy::WriteThrough,
+                "write-back" => config.write_policy = WritePolicy::WriteBack,
+                "write-allocate" => config.write_miss = WriteMissPolicy::WriteAllocate,
+                "no-write-allocate" => config.write_miss = WriteMissPolicy::NoWriteAllocate,
+                _ => match option.strip_prefix("latency=") {
+                    Some(latency) => {
+                        config.latency = latency
+                            .parse()
+                            .map_err(|_| format!("bad cache latency `{latency}`"))?
+                    }
+                    None => config.replacement = option.parse()?,
+                },
+            }
+        }
+
+        let fits = line_size
+            .checked_mul(sets)
+            .and_then(|size| size.checked_mul(ways))
+            .is_some_and(|size| size <= MEMORY_SIZE);
+        if !line_size.is_power_of_two() || !sets.is_power_of_two() || ways == 0 || !fits {
+            return Err(format!(
+                "impossible cache: {line_size} byte lines, {sets} sets, {ways} ways"
+            ));
+        }
+        if config.replacement == ReplacementKind::TreePlru && !ways.is_power_of_two() {
+            return Err("plru replacement needs a power of two number of ways".to_owned());
+        }
+        Ok(config)
+    }
+}
+
+/// What happened to the cache during a single access
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AccessOutcome {
+    pub hit: bool,
+    /// The base address of the line that was evicted to make room for the accessed line
+    pub evicted: Option<u16>,
+    /// Set if the evicted line was dirty and had to be written back to memory
+    pub writeback: bool,
+    /// Why the access missed
+    pub miss: Option<MissKind>,
+}
+
+impl AccessOutcome {
+    const HIT: Self = Self {
+        hit: true,
+        evicted: None,
+        writeback: false,
+        miss: None,
+    };
+
+    const MISS: Self = Self {
+        hit: false,
+        evicted: None,
+        writeback: false,
+        miss: None,
+    };
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Number of dirty lines written back to memory, either on eviction or by a flush
+    pub writebacks: u64,
+    pub miss_kinds: MissBreakdown,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Fraction of accesses that hit, or 0 if the cache was never accessed
+    pub fn hit_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+/// Whatever sits below a cache: another cache level or main memory
+pub trait Backing {
+    /// Fills `data` with the line starting at `base`. Returns true if the data is newer than
+    /// what main memory holds, which only happens when an exclusive level hands its line up
+    fn read_line(&mut self, base: u16, data: &mut [u8]) -> bool;
+
+    /// Accepts a line that the level above evicted
+    fn evict_line(&mut self, base: u16, data: &[u8], dirty: bool);
+
+    /// Accepts dirty data for a line that the level above is keeping
+    fn write_line(&mut self, base: u16, data: &[u8]);
+
+    /// Accepts a single byte that was written through, or around, the level above
+    fn write_byte(&mut self, addr: u16, value: u8);
+}
+
+impl Backing for [u8] {
+    fn read_line(&mut self, base: u16, data: &mut [u8]) -> bool {
+        let base = base as usize;
+        data.copy_from_slice(&self[base..base + data.len()]);
+        false
+    }
+
+    fn evict_line(&mut self, base: u16, data: &[u8], dirty: bool) {
+        if dirty {
+            self.write_line(base, data);
+        }
+    }
+
+    fn write_line(&mut self, base: u16, data: &[u8]) {
+        let base = base as usize;
+        self[base..base + data.len()].copy_from_slice(data);
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self[addr as usize] = value;
+    }
+}
+
+#[derive(Clone)]
+struct Line {
+    valid: bool,
+    /// Set when the line holds stores that have not been written to memory yet
+    dirty: bool,
+    tag: usize,
+    data: Vec<u8>,
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    /// `sets * ways` lines, with the ways of each set stored next to each other
+    lines: Vec<Line>,
+    policy: Box<dyn ReplacementPolicy>,
+    stats: CacheStats,
+    /// Boxed since it is much larger than the rest of the cache's bookkeeping
+    classifier: Box<MissClassifier>,
+}
+
+impl Cache {
+    /// Creates an empty cache using the replacement policy named in `config`.
+    ///
+    /// Panics if `line_size` or `sets` is not a power of two, if `ways` is zero or if the cache
+    /// would be larger than main memory
+    pub fn new(config: CacheConfig) -> Self {
+        let policy = config.replacement.build(config.sets, config.ways);
+        Self::with_policy(config, policy)
+    }
+
+    /// Creates an empty cache that uses a custom replacement policy, ignoring
+    /// `config.replacement`
+    pub fn with_policy(config: CacheConfig, policy: Box<dyn ReplacementPolicy>) -> Self {
+        assert!(
+            config.line_size.is_power_of_two(),
+            "line size must be a power of two"
+        );
+        assert!(
+            config.sets.is_power_of_two(),
+            "set count must be a power of two"
+        );
+        assert!(config.ways > 0, "cache must have at least one way");
+        assert!(
+            config.size() <= MEMORY_SIZE,
+            "cache cannot be larger than main memory"
+        );
+
+        let line = Line {
+            valid: false,
+            dirty: false,
+            tag: 0,
+            data: vec![0; config.line_size],
+        };
+        Self {
+            config,
+            lines: vec![line; config.sets * config.ways],
+            policy,
+            stats: CacheStats::default(),
+            classifier: Box::new(MissClassifier::new(
+                config.line_size,
+                config.sets * config.ways,
+            )),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Reads the byte at `addr`, filling its line from `next` on a miss
+    pub fn read<B: Backing + ?Sized>(&mut self, next: &mut B, addr: u16) -> (u8, AccessOutcome) {
+        let (line, outcome) = self.access(next, addr);
+        let value = self.lines[line].data[self.offset(addr)];
+        (value, outcome)
+    }
+
+    /// Writes `value` to `addr` according to the configured write policies
+    pub fn write<B: Backing + ?Sized>(
+        &mut self,
+        next: &mut B,
+        addr: u16,
+        value: u8,
+    ) -> AccessOutcome {
+        let (line, outcome) = match (self.find(addr), self.config.write_miss) {
+            (None, WriteMissPolicy::NoWriteAllocate) => {
+                next.write_byte(addr, value);
+                return AccessOutcome {
+                    miss: self.record(addr, false),
+                    ..AccessOutcome::MISS
+                };
+            }
+            _ => self.access(next, addr),
+        };
+        let offset = self.offset(addr);
+        self.lines[line].data[offset] = value;
+        match self.config.write_policy {
+            WritePolicy::WriteThrough => next.write_byte(addr, value),
+            WritePolicy::WriteBack => self.lines[line].dirty = true,
+        }
+        outcome
+    }
+
+    /// Writes every dirty line back to `next`, leaving them in the cache as clean lines.
+    ///
+    /// With a write back cache the levels below are only up to date after this is called
+    pub fn flush<B: Backing + ?Sized>(&mut self, next: &mut B) {
+        for line in 0..self.lines.len() {
+            if self.lines[line].valid && self.lines[line].dirty {
+                self.write_back(next, line);
+            }
+        }
+    }
+
+    /// Returns the value at `addr` if it is cached, without touching replacement state or stats
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        self.find(addr)
+            .map(|line| self.lines[line].data[self.offset(addr)])
+    }
+
+    /// Writes the line holding `addr` back to `next` if it is cached and dirty, without
+    /// touching replacement state. Used to keep other caches coherent with this one
+    pub fn clean<B: Backing + ?Sized>(&mut self, next: &mut B, addr: u16) {
+        if let Some(line) = self.find(addr) {
+            if self.lines[line].dirty {
+                self.write_back(next, line);
+            }
+        }
+    }
+
+    /// Drops the line holding `addr` if it is cached, discarding any dirty data
+    pub fn invalidate(&mut self, addr: u16) {
+        self.take(addr);
+    }
+
+    /// Drops every line, discarding any dirty data
+    pub fn invalidate_all(&mut self) {
+        for line in &mut self.lines {
+            line.valid = false;
+            line.dirty = false;
+        }
+    }
+
+    /// Overwrites the cached copy of `addr` without touching replacement state, stats or the
+    /// dirty bit. Returns false if the line is not cached here
+    pub(crate) fn overwrite(&mut self, addr: u16, value: u8) -> bool {
+        let Some(line) = self.find(addr) else {
+            return false;
+        };
+        let offset = self.offset(addr);
+        self.lines[line].data[offset] = value;
+        true
+    }
+
+    /// Removes the line holding `addr` from the cache, returning its data and whether it was
+    /// dirty
+    pub fn take(&mut self, addr: u16) -> Option<(Vec<u8>, bool)> {
+        let line = self.find(addr)?;
+        let line = &mut self.lines[line];
+        line.valid = false;
+        Some((line.data.clone(), std::mem::take(&mut line.dirty)))
+    }
+
+    /// Looks up the line holding `addr` on behalf of the level above, counting a hit or a miss
+    /// and returning the line's data on a hit
+    pub(crate) fn probe(&mut self, addr: u16) -> Option<&[u8]> {
+        let line = self.find(addr);
+        self.record(addr, line.is_some());
+        let line = line?;
+        self.touch(line);
+        Some(&self.lines[line].data)
+    }
+
+    /// Misses in each region of memory that has had any, along with the address the region
+    /// starts at
+    pub fn miss_regions(&self) -> Vec<(u16, MissBreakdown)> {
+        self.classifier.regions()
+    }
+
+    /// Saves every line, the statistics, the replacement policy's bookkeeping and the miss
+    /// classifier. The config is saved separately, since it is needed to build the cache that
+    /// [`Cache::restore_snapshot`] restores into
+    pub(crate) fn save_snapshot(&self, out: &mut SnapshotWriter) {
+        for line in &self.lines {
+            out.bool(line.valid);
+            out.bool(line.dirty);
+            out.usize(line.tag);
+            out.bytes(&line.data);
+        }
+        let stats = &self.stats;
+        for count in [stats.hits, stats.misses, stats.evictions, stats.writebacks] {
+            out.u64(count);
+        }
+        let kinds = &stats.miss_kinds;
+        for count in [kinds.compulsory, kinds.capacity, kinds.conflict] {
+            out.u64(count);
+        }
+        let policy = self.policy.save();
+        out.usize(policy.len());
+        for value in policy {
+            out.u64(value);
+        }
+        self.classifier.save_snapshot(out);
+    }
+
+    /// Replaces everything but the config with state saved from a cache with the same config
+    pub(crate) fn restore_snapshot(&mut self, input: &mut SnapshotReader) -> io::Result<()> {
+        let tags = MEMORY_SIZE / (self.config.line_size * self.config.sets);
+        for line in &mut self.lines {
+            line.valid = input.bool()?;
+            line.dirty = input.bool()?;
+            line.tag = input.usize(tags - 1)?;
+            if line.dirty && !line.valid {
+                return Err(invalid("cache line in snapshot cannot exist"));
+            }
+            line.data
+                .copy_from_slice(input.bytes(self.config.line_size)?);
+        }
+        self.stats = CacheStats {
+            hits: input.u64()?,
+            misses: input.u64()?,
+            evictions: input.u64()?,
+            writebacks: input.u64()?,
+            miss_kinds: MissBreakdown {
+                compulsory: input.u64()?,
+                capacity: input.u64()?,
+                conflict: input.u64()?,
+            },
+        };
+        // A clock and a value for every line is the most any policy saves
+        let lines = self.config.sets * self.config.ways;
+        let policy = (0..input.usize(lines + 1)?)
+            .map(|_| input.u64())
+            .collect::<io::Result<Vec<_>>>()?;
+        if !self.policy.restore(&policy) {
+            return Err(invalid(format!(
+                "snapshot does not hold {} replacement state for this cache",
+                self.config.replacement
+            )));
+        }
+        self.classifier.restore_snapshot(input)
+    }
+
+    /// Updates the cached copy of the line starting at `base` with data written back from the
+    /// level above. Returns false if the line is not cached here
+    pub(crate) fn update_line<B: Backing + ?Sized>(
+        &mut self,
+        next: &mut B,
+        base: u16,
+        data: &[u8],
+    ) -> bool {
+        let Some(line) = self.find(base) else {
+            return false;
+        };
+        self.lines[line].data.copy_from_slice(data);
+        match self.config.write_policy {
+            WritePolicy::WriteThrough => next.write_line(base, data),
+            WritePolicy::WriteBack => self.lines[line].dirty = true,
+        }
+        true
+    }
+
+    /// Updates the cached copy of `addr` with a byte written through the level above. Returns
+    /// false if the line is not cached here
+    pub(crate) fn update_byte<B: Backing + ?Sized>(
+        &mut self,
+        next: &mut B,
+        addr: u16,
+        value: u8,
+    ) -> bool {
+        let Some(line) = self.find(addr) else {
+            return false;
+        };
+        let offset = self.offset(addr);
+        self.lines[line].data[offset] = value;
+        match self.config.write_policy {
+            WritePolicy::WriteThrough => next.write_byte(addr, value),
+            WritePolicy::WriteBack => self.lines[line].dirty = true,
+        }
+        true
+    }
+
+    /// Places the line starting at `base` in the cache, handing whatever line it replaces to
+    /// `next`. If the line is already cached its data is overwritten instead.
+    ///
+    /// The returned outcome describes the eviction, if any, and is always a miss
+    pub(crate) fn insert<B: Backing + ?Sized>(
+        &mut self,
+        next: &mut B,
+        base: u16,
+        data: &[u8],
+        dirty: bool,
+    ) -> AccessOutcome {
+        if let Some(line) = self.find(base) {
+            self.lines[line].data.copy_from_slice(data);
+            self.lines[line].dirty |= dirty;
+            return AccessOutcome::MISS;
+        }
+
+        let set = self.set_index(base);
+        let line = self.victim(base);
+        self.policy.fill(set, line - set * self.config.ways);
+        let mut outcome = AccessOutcome::MISS;
+        if self.lines[line].valid {
+            let evicted = self.line_base(set, self.lines[line].tag);
+            let victim = &self.lines[line];
+            next.evict_line(evicted, &victim.data, victim.dirty);
+            outcome.evicted = Some(evicted);
+            outcome.writeback = victim.dirty;
+            self.stats.evictions += 1;
+            self.stats.writebacks += outcome.writeback as u64;
+        }
+
+        let tag = self.tag(base);
+        let victim = &mut self.lines[line];
+        victim.valid = true;
+        victim.dirty = dirty;
+        victim.tag = tag;
+        victim.data.copy_from_slice(data);
+        outcome
+    }
+
+    /// Makes sure the line holding `addr` is present, returning its index into `lines`
+    fn access<B: Backing + ?Sized>(&mut self, next: &mut B, addr: u16) -> (usize, AccessOutcome) {
+        let line = self.find(addr);
+        let miss = self.record(addr, line.is_some());
+        if let Some(line) = line {
+            self.touch(line);
+            return (line, AccessOutcome::HIT);
+        }
+        let base = self.base(addr);
+        let mut data = vec![0; self.config.line_size];
+        let dirty = next.read_line(base, &mut data);
+        let outcome = AccessOutcome {
+            miss,
+            ..self.insert(next, base, &data, dirty)
+        };
+        (self.find(addr).unwrap(), outcome)
+    }
+
+    /// Counts an access as a hit or a miss, classifying it if it missed
+    fn record(&mut self, addr: u16, hit: bool) -> Option<MissKind> {
+        let miss = self.classifier.access(addr, hit);
+        match miss {
+            None => self.stats.hits += 1,
+            Some(kind) => {
+                self.stats.misses += 1;
+                self.stats.miss_kinds.record(kind);
+            }
+        }
+        miss
+    }
+
+    /// Tells the replacement policy a line was used
+    fn touch(&mut self, line: usize) {
+        let set = line / self.config.ways;
+        self.policy.touch(set, line - set * self.config.ways);
+    }
+
+    /// Copies a line's data to the next level and marks it clean
+    fn write_back<B: Backing + ?Sized>(&mut self, next: &mut B, line: usize) {
+        let set = line / self.config.ways;
+        let base = self.line_base(set, self.lines[line].tag);
+        next.write_line(base, &self.lines[line].data);
+        self.lines[line].dirty = false;
+        self.stats.writebacks += 1;
+    }
+
+    pub(crate) fn find(&self, addr: u16) -> Option<usize> {
+        let tag = self.tag(addr);
+        self.set(addr)
+            .find(|&line| self.lines[line].valid && self.lines[line].tag == tag)
+    }
+
+    /// Picks the line in `addr`'s set to replace, preferring invalid lines before asking the
+    /// replacement policy
+    fn victim(&mut self, addr: u16) -> usize {
+        let mut set = self.set(addr);
+        match set.find(|&line| !self.lines[line].valid) {
+            Some(line) => line,
+            None => {
+                let set = self.set_index(addr);
+                set * self.config.ways + self.policy.victim(set)
+            }
+        }
+    }
+
+    /// Indices into `lines` that make up the set `addr` maps to
+    fn set(&self, addr: u16) -> std::ops::Range<usize> {
+        let first = self.set_index(addr) * self.config.ways;
+        first..first + self.config.ways
+    }
+
+    /// The address of the first byte in the line holding `addr`
+    pub fn base(&self, addr: u16) -> u16 {
+        (addr as usize & !(self.config.line_size - 1)) as u16
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        addr as usize & (self.config.line_size - 1)
+    }
+
+    fn set_index(&self, addr: u16) -> usize {
+        (addr as usize / self.config.line_size) & (self.config.sets - 1)
+    }
+
+    fn tag(&self, addr: u16) -> usize {
+        addr as usize / (self.config.line_size * self.config.sets)
+    }
+
+    fn line_base(&self, set: usize, tag: usize) -> u16 {
+        ((tag * self.config.sets + set) * self.config.line_size) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Vec<u8> {
+        (0..MEMORY_SIZE).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn hits_after_first_miss() {
+        let mut memory = memory();
+        let mut cache = Cache::new(CacheConfig::new(4, 4, 1));
+        let (value, outcome) = cache.read(&mut memory[..], 0x101);
+        assert_eq!(value, 0x01);
+        assert!(!outcome.hit);
+
+        // Same line
+        let (value, outcome) = cache.read(&mut memory[..], 0x103);
+        assert_eq!(value, 0x03);
+        assert!(outcome.hit);
+        assert_eq!(
+            *cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                writebacks: 0,
+                miss_kinds: MissBreakdown {
+                    compulsory: 1,
+                    capacity: 0,
+                    conflict: 0
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn base_of_a_line_spanning_memory() {
+        let cache = Cache::new(CacheConfig::new(MEMORY_SIZE, 1, 1));
+        assert_eq!(cache.base(0xFFFF), 0x0000);
+        let cache = Cache::new(CacheConfig::new(4, 4, 1));
+        assert_eq!(cache.base(0x1237), 0x1234);
+    }
+
+    #[test]
+    fn parses_configs() {
+        assert_eq!("16x16x2".parse(), Ok(CacheConfig::default()));
+        assert_eq!(
+            "4x64x1,fifo,write-through,no-write-allocate,latency=3".parse(),
+            Ok(CacheConfig::new(4, 64, 1)
+                .with_replacement(ReplacementKind::Fifo)
+                .with_write_policy(WritePolicy::WriteThrough, WriteMissPolicy::NoWriteAllocate)
+                .with_latency(3))
+        );
+        for bad in [
+            "16x16",
+            "16x16x2x2",
+            "3x16x2",
+            "16x16x0",
+            "256x256x2",
+            "16x16x3,plru",
+        ] {
+            assert!(bad.parse::<CacheConfig>().is_err(), "{bad}");
+        }
+        assert!("16x16x2,mru".parse::<CacheConfig>().is_err());
+        assert!("16x16x2,latency=x".parse::<CacheConfig>().is_err());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut memory = memory();
+        // One set so every line conflicts
+        let mut cache = Cache::new(CacheConfig::new(4, 1, 2));
+        cache.read(&mut memory[..], 0x00);
+        cache.read(&mut memory[..], 0x10);
+        cache.read(&mut memory[..], 0x00);
+
+        let (_, outcome) = cache.read(&mut memory[..], 0x20);
+        assert_eq!(
+            outcome,
+            AccessOutcome {
+                hit: false,
+                evicted: Some(0x10),
+                writeback: false,
+                miss: Some(MissKind::Compulsory),
+            }
+        );
+        assert!(cache.read(&mut memory[..], 0x00).1.hit);
+    }
+
+    #[test]
+    fn uses_configured_policy() {
+        let mut memory = memory();
+        let config = CacheConfig::new(4, 1, 2).with_replacement(ReplacementKind::Fifo);
+        let mut cache = Cache::new(config);
+        cache.read(&mut memory[..], 0x00);
+        cache.read(&mut memory[..], 0x10);
+        cache.read(&mut memory[..], 0x00);
+
+        // Unlike lru, the hit on 0x00 does not save it
+        let (_, outcome) = cache.read(&mut memory[..], 0x20);
+        assert_eq!(outcome.evicted, Some(0x00));
+    }
+
+    #[test]
+    fn write_through_updates_cache_and_memory() {
+        let mut memory = memory();
+        let config = CacheConfig::default()
+            .with_write_policy(WritePolicy::WriteThrough, WriteMissPolicy::WriteAllocate);
+        let mut cache = Cache::new(config);
+        cache.write(&mut memory[..], 0x2000, 0xAB);
+        assert_eq!(memory[0x2000], 0xAB);
+        assert_eq!(cache.peek(0x2000), Some(0xAB));
+        assert_eq!(
+            cache.read(&mut memory[..], 0x2000),
+            (0xAB, AccessOutcome::HIT)
+        );
+    }
+
+    #[test]
+    fn write_back_defers_until_eviction() {
+        let mut memory = memory();
+        let mut cache = Cache::new(CacheConfig::new(4, 1, 1));
+        cache.write(&mut memory[..], 0x2000, 0xAB);
+        assert_eq!(memory[0x2000], 0x00);
+        assert_eq!(cache.peek(0x2000), Some(0xAB));
+
+        let (_, outcome) = cache.read(&mut memory[..], 0x3000);
+        assert_eq!(
+            outcome,
+            AccessOutcome {
+                hit: false,
+                evicted: Some(0x2000),
+                writeback: true,
+                miss: Some(MissKind::Compulsory),
+            }
+        );
+        assert_eq!(memory[0x2000], 0xAB);
+        assert_eq!(cache.stats().writebacks, 1);
+    }
+
+    #[test]
+    fn flush_writes_dirty_lines() {
+        let mut memory = memory();
+        let mut cache = Cache::new(CacheConfig::default());
+        cache.write(&mut memory[..], 0x2000, 0xAB);
+        cache.write(&mut memory[..], 0x2001, 0xCD);
+        cache.flush(&mut memory[..]);
+        assert_eq!(&memory[0x2000..0x2002], &[0xAB, 0xCD]);
+        assert_eq!(cache.stats().writebacks, 1);
+
+        // Lines are clean now, so flushing again does nothing
+        cache.flush(&mut memory[..]);
+        assert_eq!(cache.stats().writebacks, 1);
+    }
+
+    #[test]
+    fn no_write_allocate_bypasses_cache() {
+        let mut memory = memory();
+        let config = CacheConfig::default()
+            .with_write_policy(WritePolicy::WriteBack, WriteMissPolicy::NoWriteAllocate);
+        let mut cache = Cache::new(config);
+        assert_eq!(
+            cache.write(&mut memory[..], 0x2000, 0xAB),
+            AccessOutcome {
+                miss: Some(MissKind::Compulsory),
+                ..AccessOutcome::MISS
+            }
+        );
+        assert_eq!(memory[0x2000], 0xAB);
+        assert_eq!(cache.peek(0x2000), None);
+
+        // Once the line is cached, write hits stay in the cache
+        cache.read(&mut memory[..], 0x2000);
+        assert!(cache.write(&mut memory[..], 0x2000, 0xCD).hit);
+        assert_eq!(memory[0x2000], 0xAB);
+    }
+}
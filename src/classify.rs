@@ -0,0 +1,233 @@
+//! Sorts cache misses into the three Cs: compulsory, capacity and conflict
+
+use crate::snapshot::{invalid, SnapshotReader, SnapshotWriter};
+use crate::MEMORY_SIZE;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+/// Misses are also broken down by which aligned block of memory this size they fall in
+pub const MISS_REGION_SIZE: usize = 0x1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MissKind {
+    /// The line was never accessed before, so no cache could have held it
+    Compulsory,
+    /// Even a fully associative cache of the same size would have missed
+    Capacity,
+    /// A fully associative cache of the same size would have hit, so the miss comes from too
+    /// many lines mapping to the same set
+    Conflict,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct MissBreakdown {
+    pub compulsory: u64,
+    pub capacity: u64,
+    pub conflict: u64,
+}
+
+impl MissBreakdown {
+    pub fn total(&self) -> u64 {
+        self.compulsory + self.capacity + self.conflict
+    }
+
+    pub fn record(&mut self, kind: MissKind) {
+        match kind {
+            MissKind::Compulsory => self.compulsory += 1,
+            MissKind::Capacity => self.capacity += 1,
+            MissKind::Conflict => self.conflict += 1,
+        }
+    }
+}
+
+impl fmt::Display for MissBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} compulsory, {} capacity, {} conflict",
+            self.compulsory, self.capacity, self.conflict
+        )
+    }
+}
+
+/// Runs a fully associative lru cache with as many lines as the real cache alongside it, and
+/// uses it to classify the real cache's misses
+pub struct MissClassifier {
+    line_size: usize,
+    capacity: usize,
+    clock: u64,
+    /// When each line was last used, if it is in the shadow cache. Indexed by line number
+    last_used: Vec<Option<u64>>,
+    /// Every line in the shadow cache, keyed by when it was last used so the first entry is the
+    /// lru line
+    lines: BTreeMap<u64, usize>,
+    /// Which lines have ever been accessed
+    seen: Vec<bool>,
+    /// Misses keyed by the index of the region they fall in
+    regions: BTreeMap<usize, MissBreakdown>,
+}
+
+impl MissClassifier {
+    /// Creates a classifier for a cache with `lines` lines of `line_size` bytes each
+    pub fn new(line_size: usize, lines: usize) -> Self {
+        let line_count = MEMORY_SIZE / line_size;
+        Self {
+            line_size,
+            capacity: lines,
+            clock: 0,
+            last_used: vec![None; line_count],
+            lines: BTreeMap::new(),
+            seen: vec![false; line_count],
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Records an access to `addr` by the real cache, returning what kind of miss it was if
+    /// the real cache missed
+    pub fn access(&mut self, addr: u16, hit: bool) -> Option<MissKind> {
+        let line = addr as usize / self.line_size;
+        self.clock += 1;
+        let shadow_hit = match self.last_used[line].replace(self.clock) {
+            Some(last_used) => {
+                self.lines.remove(&last_used);
+                true
+            }
+            None => false,
+        };
+        self.lines.insert(self.clock, line);
+        if self.lines.len() > self.capacity {
+            let (_, lru) = self.lines.pop_first().unwrap();
+            self.last_used[lru] = None;
+        }
+        let first_access = !std::mem::replace(&mut self.seen[line], true);
+
+        if hit {
+            return None;
+        }
+        let kind = if first_access {
+            MissKind::Compulsory
+        } else if shadow_hit {
+            MissKind::Conflict
+        } else {
+            MissKind::Capacity
+        };
+        self.regions
+            .entry(addr as usize / MISS_REGION_SIZE)
+            .or_default()
+            .record(kind);
+        Some(kind)
+    }
+
+    /// Misses in each region that has had any, along with the address the region starts at
+    pub fn regions(&self) -> Vec<(u16, MissBreakdown)> {
+        self.regions
+            .iter()
+            .map(|(&region, &misses)| ((region * MISS_REGION_SIZE) as u16, misses))
+            .collect()
+    }
+
+    /// Saves the shadow cache, which lines have been seen and the misses in each region. Lines
+    /// missing from the shadow cache are saved as last used at 0, which `clock` never is
+    pub(crate) fn save_snapshot(&self, out: &mut SnapshotWriter) {
+        out.u64(self.clock);
+        for last_used in &self.last_used {
+            out.u64(last_used.unwrap_or(0));
+        }
+        for &seen in &self.seen {
+            out.bool(seen);
+        }
+        out.usize(self.regions.len());
+        for (&region, misses) in &self.regions {
+            out.usize(region);
+            out.u64(misses.compulsory);
+            out.u64(misses.capacity);
+            out.u64(misses.conflict);
+        }
+    }
+
+    /// Replaces everything with state saved from a classifier of the same size
+    pub(crate) fn restore_snapshot(&mut self, input: &mut SnapshotReader) -> io::Result<()> {
+        self.clock = input.u64()?;
+        self.lines.clear();
+        for line in 0..self.last_used.len() {
+            self.last_used[line] = match input.u64()? {
+                0 => None,
+                last_used if last_used <= self.clock => {
+                    self.lines.insert(last_used, line);
+                    Some(last_used)
+                }
+                _ => return Err(invalid("miss classifier line used in the future")),
+            };
+        }
+        if self.lines.len() != self.last_used.iter().flatten().count()
+            || self.lines.len() > self.capacity
+        {
+            return Err(invalid("miss classifier's shadow cache is inconsistent"));
+        }
+        for seen in &mut self.seen {
+            *seen = input.bool()?;
+        }
+        self.regions.clear();
+        let regions = MEMORY_SIZE / MISS_REGION_SIZE;
+        for _ in 0..input.usize(regions)? {
+            let region = input.usize(regions - 1)?;
+            let misses = MissBreakdown {
+                compulsory: input.u64()?,
+                capacity: input.u64()?,
+                conflict: input.u64()?,
+            };
+            self.regions.insert(region, misses);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_misses() {
+        // Two line shadow cache
+        let mut classifier = MissClassifier::new(4, 2);
+        assert_eq!(classifier.access(0x00, false), Some(MissKind::Compulsory));
+        assert_eq!(classifier.access(0x04, false), Some(MissKind::Compulsory));
+        // Both lines still fit in a fully associative cache
+        assert_eq!(classifier.access(0x00, false), Some(MissKind::Conflict));
+        assert_eq!(classifier.access(0x08, false), Some(MissKind::Compulsory));
+        // 0x04 was the lru line, so it was pushed out of the shadow cache too
+        assert_eq!(classifier.access(0x04, false), Some(MissKind::Capacity));
+        assert_eq!(classifier.access(0x05, true), None);
+    }
+
+    #[test]
+    fn groups_misses_by_region() {
+        let mut classifier = MissClassifier::new(4, 2);
+        classifier.access(0x0000, false);
+        classifier.access(0x2000, false);
+        classifier.access(0x2004, false);
+        classifier.access(0x0000, false);
+        assert_eq!(
+            classifier.regions(),
+            vec![
+                (
+                    0x0000,
+                    MissBreakdown {
+                        compulsory: 1,
+                        capacity: 1,
+                        conflict: 0
+                    }
+                ),
+                (
+                    0x2000,
+                    MissBreakdown {
+                        compulsory: 2,
+                        capacity: 0,
+                        conflict: 0
+                    }
+                ),
+            ]
+        );
+    }
+}
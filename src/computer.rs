@@ -1,140 +1,761 @@
-use crate::*;
-use crate::{try_parse, DstTarget, Instruction, MathFunction};
+use crate::history::{History, UndoStep};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::{
+    operand_widths, try_parse, AccessKind, AccessSource, BranchKind, CacheConfig, CpuRegister,
+    DstTarget, EmulatorError, Event, Instruction, InstructionCosts, MathFunction, MemoryMethod,
+    MemorySystem, Observer, Register, Silent, SrcTarget, TimingReport, TraceEntry, WatchHit,
+    Watchpoint, MAX_INSTRUCTION_SIZE,
+};
 
 pub const MEMORY_SIZE: usize = 64 * 1024;
 
 pub struct Computer {
-    memory: [u8; MEMORY_SIZE],
+    memory: MemorySystem,
     acc: u8,
     ir: u8,
     mar: u16,
     pc: u16,
+    /// Address of the instruction currently being executed
+    instruction_pc: u16,
+    /// The opcode and operand bytes of the instruction currently being executed, zero padded
+    instruction_bytes: [u8; MAX_INSTRUCTION_SIZE],
+    /// Set to the address of the instruction that fetched the last byte of memory, leaving pc
+    /// nowhere to go. Fetching anything more through pc before a jump fails
+    pc_past_end: Option<u16>,
+    costs: InstructionCosts,
+    /// Number of instructions executed so far
+    instructions: u64,
+    /// Cycles charged for executing instructions so far, not counting memory accesses
+    execute_cycles: u64,
+    /// Every memory access made so far, if recording was enabled with [`Computer::record_trace`]
+    trace: Option<Vec<TraceEntry>>,
+    /// Address of the instruction that last wrote each byte, if recording was enabled with
+    /// [`Computer::record_writers`]
+    writers: Option<Vec<Option<u16>>>,
+    /// Undo steps for recent instructions, if enabled with [`Computer::record_history`]
+    history: Option<History>,
+    watchpoints: Vec<Watchpoint>,
+    /// The first watchpoint fired by the instruction executed last
+    watch_hit: Option<WatchHit>,
+    observer: Box<dyn Observer>,
+    /// Set once a hault instruction executes, after which nothing else will
+    halted: bool,
 }
 
-#[derive(PartialEq, Eq)]
+/// A snapshot of the cpu's registers
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct CpuState {
+    pub pc: u16,
+    pub ir: u8,
+    pub acc: u8,
+    pub mar: u16,
+}
+
+impl CpuState {
+    /// Starts executing at `pc` with every other register zeroed
+    pub fn new(pc: u16) -> Self {
+        Self {
+            pc,
+            ..Self::default()
+        }
+    }
+}
+
+/// What happened when a single instruction was executed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ExecuteResult {
     Continue,
     Hault,
 }
 
+/// Why a bounded run stopped
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    /// A hault instruction was executed
+    Halted,
+    /// The requested number of instructions were executed
+    InstructionLimit,
+    /// At least the requested number of cycles passed
+    CycleLimit,
+    /// The predicate given to [`Computer::run_until`] returned true
+    Condition,
+    /// A watchpoint fired, see [`Computer::watch_hit`]
+    Watchpoint,
+}
+
 impl Computer {
+    /// Creates a computer without any caches
     pub fn new(memory: [u8; MEMORY_SIZE]) -> Self {
+        Self::with_memory_system(MemorySystem::new(memory))
+    }
+
+    /// Starts executing at memory address 0, and runs until a hault instruction is encountered,
+    /// a watchpoint fires or the cpu faults.
+    ///
+    /// Any dirty cache lines are flushed to memory once execution halts or faults. Stopping at a
+    /// watchpoint leaves the caches alone, so that running on gives the same cache statistics;
+    /// use [`Computer::peek`] to see memory from there
+    pub fn run(&mut self) -> Result<StopReason, EmulatorError> {
+        let result = self.run_until(|_| false);
+        if result != Ok(StopReason::Watchpoint) {
+            self.flush();
+        }
+        result
+    }
+
+    /// Fetches and executes a single instruction. Once the cpu has halted this does nothing and
+    /// keeps returning [`ExecuteResult::Hault`].
+    ///
+    /// Unlike [`Computer::run`], this does not flush the caches
+    pub fn step(&mut self) -> Result<ExecuteResult, EmulatorError> {
+        self.watch_hit = None;
+        if self.halted {
+            return Ok(ExecuteResult::Hault);
+        }
+        let step = UndoStep {
+            state: self.state(),
+            instruction_pc: self.instruction_pc,
+            pc_past_end: self.pc_past_end,
+            halted: self.halted,
+            instructions: self.instructions,
+            execute_cycles: self.execute_cycles,
+            writes: vec![],
+        };
+        if let Some(history) = &mut self.history {
+            history.push(step);
+        }
+        self.fetch_instruction()?;
+        let result = self.execute_instruction()?;
+        self.halted = result == ExecuteResult::Hault;
+        Ok(result)
+    }
+
+    /// Executes at most `instructions` instructions
+    pub fn run_for(&mut self, instructions: u64) -> Result<StopReason, EmulatorError> {
+        for _ in 0..instructions {
+            if let Some(reason) = self.step_stopping()? {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::InstructionLimit)
+    }
+
+    /// Executes instructions until `condition` returns true, checking it after each one
+    pub fn run_until(
+        &mut self,
+        mut condition: impl FnMut(&Computer) -> bool,
+    ) -> Result<StopReason, EmulatorError> {
+        loop {
+            if let Some(reason) = self.step_stopping()? {
+                return Ok(reason);
+            }
+            if condition(self) {
+                return Ok(StopReason::Condition);
+            }
+        }
+    }
+
+    /// Executes instructions until at least `cycles` cycles have passed. The last instruction is
+    /// always run to completion, so this can overshoot
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<StopReason, EmulatorError> {
+        let end = self.cycles().saturating_add(cycles);
+        while self.cycles() < end {
+            if let Some(reason) = self.step_stopping()? {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::CycleLimit)
+    }
+
+    /// Steps once, returning why execution should stop if it should
+    fn step_stopping(&mut self) -> Result<Option<StopReason>, EmulatorError> {
+        Ok(match self.step()? {
+            ExecuteResult::Hault => Some(StopReason::Halted),
+            ExecuteResult::Continue if self.watch_hit.is_some() => Some(StopReason::Watchpoint),
+            ExecuteResult::Continue => None,
+        })
+    }
+
+    /// Starts keeping an undo log of the last `limit` instructions executed, forgetting anything
+    /// recorded before. Each instruction costs its registers plus the old value of each byte it
+    /// writes, rather than a copy of memory
+    pub fn record_history(&mut self, limit: usize) {
+        self.history = Some(History::new(limit));
+    }
+
+    /// Number of instructions that can currently be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Undoes the last instruction executed, restoring the registers, memory, instruction count
+    /// and execute cycles from before it. Returns false if there is nothing left to undo.
+    ///
+    /// Cache contents and statistics, traces and last writers are not rewound, and changes made
+    /// directly rather than by executing instructions are not undone
+    pub fn step_back(&mut self) -> bool {
+        let Some(step) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        for &(addr, old) in step.writes.iter().rev() {
+            self.memory.poke(addr, old);
+        }
+        let CpuState { pc, ir, acc, mar } = step.state;
+        (self.pc, self.ir, self.acc, self.mar) = (pc, ir, acc, mar);
+        self.instruction_pc = step.instruction_pc;
+        self.pc_past_end = step.pc_past_end;
+        self.halted = step.halted;
+        self.instructions = step.instructions;
+        self.execute_cycles = step.execute_cycles;
+        self.watch_hit = None;
+        true
+    }
+
+    /// Steps back until the cpu is about to execute the instruction at `pc` again. Returns false,
+    /// having undone everything it could, if the history runs out first
+    pub fn run_back_to(&mut self, pc: u16) -> bool {
+        while self.step_back() {
+            if self.pc == pc {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Steps back until only `instructions` instructions have been executed. Returns false
+    /// without changing anything if fewer have been executed, or having undone everything it
+    /// could if the history runs out first
+    pub fn rewind_to(&mut self, instructions: u64) -> bool {
+        if instructions > self.instructions {
+            return false;
+        }
+        while self.instructions > instructions {
+            if !self.step_back() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether a hault instruction has been executed
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            ir: self.ir,
+            acc: self.acc,
+            mar: self.mar,
+        }
+    }
+
+    /// Sets every register. Like [`Computer::set_pc`], this lets a halted cpu run again
+    pub fn set_state(&mut self, state: CpuState) {
+        self.set_pc(state.pc);
+        self.set_ir(state.ir);
+        self.set_acc(state.acc);
+        self.set_mar(state.mar);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn ir(&self) -> u8 {
+        self.ir
+    }
+
+    pub fn acc(&self) -> u8 {
+        self.acc
+    }
+
+    pub fn mar(&self) -> u16 {
+        self.mar
+    }
+
+    /// Creates a computer without any caches, with its registers set to `state`
+    pub fn with_state(memory: [u8; MEMORY_SIZE], state: CpuState) -> Self {
+        let mut computer = Self::new(memory);
+        computer.set_state(state);
+        computer
+    }
+
+    /// Creates a computer whose memory accesses all go through a cache with the given geometry
+    pub fn with_cache(memory: [u8; MEMORY_SIZE], config: CacheConfig) -> Self {
+        Self::with_memory_system(MemorySystem::unified(memory, config))
+    }
+
+    /// Creates a computer with separate instruction and data caches
+    pub fn with_split_caches(
+        memory: [u8; MEMORY_SIZE],
+        instruction: CacheConfig,
+        data: CacheConfig,
+    ) -> Self {
+        Self::with_memory_system(MemorySystem::split(memory, instruction, data))
+    }
+
+    pub fn with_memory_system(memory: MemorySystem) -> Self {
         Self {
             memory,
             acc: 0,
             ir: 0,
             mar: 0,
             pc: 0,
+            instruction_pc: 0,
+            instruction_bytes: [0; MAX_INSTRUCTION_SIZE],
+            pc_past_end: None,
+            costs: InstructionCosts::default(),
+            instructions: 0,
+            execute_cycles: 0,
+            trace: None,
+            writers: None,
+            history: None,
+            watchpoints: vec![],
+            watch_hit: None,
+            observer: Box::new(Silent),
+            halted: false,
         }
     }
 
-    /// Starts executing at memory address 0, and runs until a hault instruction is encountered
-    pub fn run(&mut self) {
-        loop {
-            println!();
-            self.fetch_instruction();
-            println!();
-            match self.execute_instruction() {
-                Ok(ExecuteResult::Hault) => break,
-                Ok(ExecuteResult::Continue) => continue,
-                Err(()) => {
-                    break;
-                }
-            }
+    /// Sends every execution event to `observer`. By default events are ignored
+    pub fn set_observer(&mut self, observer: impl Observer + 'static) {
+        self.observer = Box::new(observer);
+    }
+
+    /// The first error the observer hit, after which it stops receiving events
+    pub fn observer_error(&self) -> Option<&std::io::Error> {
+        self.observer.error()
+    }
+
+    /// Starts recording every memory access the cpu makes, discarding anything recorded before
+    pub fn record_trace(&mut self) {
+        self.trace = Some(vec![]);
+    }
+
+    /// Accesses recorded since [`Computer::record_trace`] was called
+    pub fn trace(&self) -> Option<&[TraceEntry]> {
+        self.trace.as_deref()
+    }
+
+    /// Starts recording which instruction writes each byte of memory, forgetting anything
+    /// recorded before
+    pub fn record_writers(&mut self) {
+        self.writers = Some(vec![None; MEMORY_SIZE]);
+    }
+
+    /// Address of the instruction that last wrote `addr` since [`Computer::record_writers`] was
+    /// called, or `None` if it has not been written or recording is off
+    pub fn last_writer(&self, addr: u16) -> Option<u16> {
+        self.writers.as_ref()?[addr as usize]
+    }
+
+    /// Stops execution whenever an instruction makes a data access matching `watchpoint`,
+    /// returning its index. Fetching instructions and their operands does not count
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.watchpoints.len() - 1
+    }
+
+    /// Removes the watchpoint at `index`, moving any after it down one place
+    pub fn remove_watchpoint(&mut self, index: usize) -> Watchpoint {
+        self.watchpoints.remove(index)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// The watchpoint fired by the instruction executed last, if any
+    pub fn watch_hit(&self) -> Option<&WatchHit> {
+        self.watch_hit.as_ref()
+    }
+
+    /// Changes how many cycles each kind of instruction is charged
+    pub fn set_instruction_costs(&mut self, costs: InstructionCosts) {
+        self.costs = costs;
+    }
+
+    /// Total number of cycles executed so far, including time spent waiting on memory
+    pub fn cycles(&self) -> u64 {
+        self.execute_cycles + self.memory.cycles()
+    }
+
+    pub fn timing(&self) -> TimingReport {
+        TimingReport {
+            instructions: self.instructions,
+            execute_cycles: self.execute_cycles,
+            memory_cycles: self.memory.cycles(),
         }
     }
 
+    /// Main memory. With a write back cache this can be stale until [`Computer::flush`] is called
     pub fn memory(&self) -> &[u8] {
+        self.memory.memory()
+    }
+
+    /// Main memory, for changing it directly. The caches are flushed and emptied first, see
+    /// [`MemorySystem::memory_mut`]
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.memory.memory_mut()
+    }
+
+    /// The current value at `addr`, including data not yet written back from the caches. This
+    /// does not count as a memory access
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.memory.peek(addr)
+    }
+
+    /// Changes the value at `addr` everywhere it is stored, without counting a memory access
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.memory.poke(addr, value);
+    }
+
+    pub fn memory_system(&self) -> &MemorySystem {
         &self.memory
     }
 
+    /// Writes any dirty cache lines back to main memory
+    pub fn flush(&mut self) {
+        self.memory.flush();
+    }
+
+    /// Saves the registers, counters and instruction costs, followed by the memory system
+    pub(crate) fn save_snapshot(&self, out: &mut SnapshotWriter) {
+        out.u16(self.pc);
+        out.u8(self.ir);
+        out.u8(self.acc);
+        out.u16(self.mar);
+        out.u16(self.instruction_pc);
+        out.bool(self.pc_past_end.is_some());
+        out.u16(self.pc_past_end.unwrap_or(0));
+        out.bool(self.halted);
+        out.u64(self.instructions);
+        out.u64(self.execute_cycles);
+        let costs = &self.costs;
+        let costs = [
+            costs.math,
+            costs.load,
+            costs.store,
+            costs.branch,
+            costs.nop,
+            costs.hault,
+        ];
+        for cost in costs {
+            out.u64(cost);
+        }
+        self.memory.save_snapshot(out);
+    }
+
+    /// Rebuilds a computer saved by [`Computer::save_snapshot`]
+    pub(crate) fn load_snapshot(input: &mut SnapshotReader) -> std::io::Result<Self> {
+        let state = CpuState {
+            pc: input.u16()?,
+            ir: input.u8()?,
+            acc: input.u8()?,
+            mar: input.u16()?,
+        };
+        let instruction_pc = input.u16()?;
+        let past_end = input.bool()?;
+        let pc_past_end = past_end.then_some(input.u16()?);
+        let halted = input.bool()?;
+        let instructions = input.u64()?;
+        let execute_cycles = input.u64()?;
+        let costs = InstructionCosts {
+            math: input.u64()?,
+            load: input.u64()?,
+            store: input.u64()?,
+            branch: input.u64()?,
+            nop: input.u64()?,
+            hault: input.u64()?,
+        };
+        let mut computer = Self::with_memory_system(MemorySystem::load_snapshot(input)?);
+        computer.set_state(state);
+        computer.instruction_pc = instruction_pc;
+        computer.pc_past_end = pc_past_end;
+        computer.halted = halted;
+        computer.instructions = instructions;
+        computer.execute_cycles = execute_cycles;
+        computer.costs = costs;
+        Ok(computer)
+    }
+
+    fn record(&mut self, addr: u16, size: u8, kind: AccessKind, source: AccessSource) {
+        if let (Some(writers), AccessKind::Write) = (&mut self.writers, kind) {
+            let start = addr as usize;
+            writers[start..start + size as usize].fill(Some(self.instruction_pc));
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEntry {
+                addr,
+                size,
+                kind,
+                source,
+            });
+        }
+    }
+
+    pub fn set_ir(&mut self, value: u8) {
+        self.observer.event(&Event::RegisterChanged {
+            register: CpuRegister::Ir,
+            old: self.ir as u16,
+            new: value as u16,
+        });
+        self.ir = value;
+    }
+
+    pub fn set_acc(&mut self, value: u8) {
+        self.observer.event(&Event::RegisterChanged {
+            register: CpuRegister::Acc,
+            old: self.acc as u16,
+            new: value as u16,
+        });
+        self.acc = value;
+    }
+
+    pub fn set_mar(&mut self, value: u16) {
+        self.observer.event(&Event::RegisterChanged {
+            register: CpuRegister::Mar,
+            old: self.mar,
+            new: value,
+        });
+        self.mar = value;
+    }
+
+    /// Jumps to `addr`. If the cpu had halted, it will run again from there
+    pub fn set_pc(&mut self, addr: u16) {
+        self.observer.event(&Event::RegisterChanged {
+            register: CpuRegister::Pc,
+            old: self.pc,
+            new: addr,
+        });
+        self.pc = addr;
+        self.pc_past_end = None;
+        self.halted = false;
+    }
+
+    /// Fails if a `size` byte access starting at `addr` would run past the end of memory
+    fn check_range(&self, addr: u16, size: u8) -> Result<(), EmulatorError> {
+        match addr.checked_add(size as u16 - 1) {
+            Some(_) => Ok(()),
+            None => Err(EmulatorError::MemoryOutOfRange {
+                addr,
+                size,
+                pc: self.instruction_pc,
+            }),
+        }
+    }
+
+    /// Fails if pc has run past the end of memory
+    fn check_pc(&self) -> Result<(), EmulatorError> {
+        match self.pc_past_end {
+            Some(pc) => Err(EmulatorError::PcOverflow { pc }),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves pc past `size` bytes that were just fetched. If they were the last bytes in memory
+    /// pc wraps around to 0, and the next fetch through it fails instead of reading from there
+    fn advance_pc(&mut self, size: u16) {
+        let (pc, wrapped) = self.pc.overflowing_add(size);
+        self.pc = pc;
+        if wrapped {
+            self.pc_past_end = Some(self.instruction_pc);
+        }
+    }
+
     /// Loads the next instruction into ir and advances pc
-    fn fetch_instruction(&mut self) {
-        self.ir = self.fetch_8_pc();
+    fn fetch_instruction(&mut self) -> Result<(), EmulatorError> {
+        self.instruction_pc = self.pc;
+        self.ir = self.fetch_8_pc()?;
+        self.observer.event(&Event::Fetched {
+            pc: self.instruction_pc,
+            opcode: self.ir,
+        });
+        Ok(())
     }
 
     /// Reads a byte by loading the address pointed to by pc and increments pc
-    fn fetch_8_pc(&mut self) -> u8 {
+    fn fetch_8_pc(&mut self) -> Result<u8, EmulatorError> {
+        self.check_pc()?;
         let pc = self.pc;
-        let a = self.memory[self.pc as usize];
-        println!("fetched 8 bits: 0x{a:X} from current pc: 0x{:X}", pc);
-        self.pc += 1;
-        a
+        self.record(pc, 1, AccessKind::Read, AccessSource::Instruction);
+        let a = self.memory.read(pc, AccessSource::Instruction);
+        self.observer.event(&Event::MemoryRead {
+            addr: pc,
+            size: 1,
+            value: a as u16,
+            source: AccessSource::Instruction,
+        });
+        self.advance_pc(1);
+        Ok(a)
     }
 
     /// Reads the next 16 bits as a big endian unsigned integer after the current pc, advancing it
     /// by 2 bytes
-    fn fetch_16_pc(&mut self) -> u16 {
+    fn fetch_16_pc(&mut self) -> Result<u16, EmulatorError> {
+        self.check_pc()?;
         let pc = self.pc;
-        let a = u16::from_be_bytes([self.memory[pc as usize], self.memory[(pc + 1) as usize]]);
-        println!("fetched 16 bits: 0x{a:X} from current pc: 0x{:X}", pc);
-        self.pc += 2;
-        a
+        self.check_range(pc, 2)?;
+        self.record(pc, 2, AccessKind::Read, AccessSource::Instruction);
+        let a = u16::from_be_bytes([
+            self.memory.read(pc, AccessSource::Instruction),
+            self.memory.read(pc + 1, AccessSource::Instruction),
+        ]);
+        self.observer.event(&Event::MemoryRead {
+            addr: pc,
+            size: 2,
+            value: a,
+            source: AccessSource::Instruction,
+        });
+        self.advance_pc(2);
+        Ok(a)
     }
 
     /// Fetches 8 bits from the given address
-    fn fetch_8(&self, addr: u16) -> u8 {
-        let a = self.memory[addr as usize];
-        println!("fetched 8 bits: 0x{a:X} from [0x{addr:X}]");
+    fn fetch_8(&mut self, addr: u16) -> u8 {
+        self.record(addr, 1, AccessKind::Read, AccessSource::Data);
+        let a = self.memory.read(addr, AccessSource::Data);
+        self.watch(addr, 1, AccessKind::Read, a as u16, a as u16);
+        self.observer.event(&Event::MemoryRead {
+            addr,
+            size: 1,
+            value: a as u16,
+            source: AccessSource::Data,
+        });
         a
     }
 
     /// Fetches 16 bits from the given address
-    fn fetch_16(&self, addr: u16) -> u16 {
-        let high = self.memory[addr as usize];
-        let low = self.memory[addr as usize + 1];
+    fn fetch_16(&mut self, addr: u16) -> Result<u16, EmulatorError> {
+        self.check_range(addr, 2)?;
+        self.record(addr, 2, AccessKind::Read, AccessSource::Data);
+        let high = self.memory.read(addr, AccessSource::Data);
+        let low = self.memory.read(addr + 1, AccessSource::Data);
         let a = u16::from_be_bytes([high, low]);
-        println!("fetched 16 bits: 0x{a:X} from [0x{addr:X}]");
-        a
+        self.watch(addr, 2, AccessKind::Read, a, a);
+        self.observer.event(&Event::MemoryRead {
+            addr,
+            size: 2,
+            value: a,
+            source: AccessSource::Data,
+        });
+        Ok(a)
+    }
+
+    /// Remembers the first watchpoint matching a data access by the current instruction
+    fn watch(&mut self, addr: u16, size: u8, kind: AccessKind, old: u16, new: u16) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let Some(watchpoint) = self
+            .watchpoints
+            .iter()
+            .position(|watchpoint| watchpoint.matches(addr, size, kind, new))
+        else {
+            return;
+        };
+        self.watch_hit = Some(WatchHit {
+            watchpoint,
+            pc: self.instruction_pc,
+            instruction: try_parse(self.ir).expect("only decoded instructions access data"),
+            bytes: self.instruction_bytes,
+            addr,
+            size,
+            kind,
+            old,
+            new,
+        });
     }
 
     /// Stores `value` into `addr`
     fn store_8(&mut self, addr: u16, value: u8) {
-        println!("storing 8 bits: 0x{value:X} to [0x{addr:X}]");
-        self.memory[addr as usize] = value;
+        self.record(addr, 1, AccessKind::Write, AccessSource::Data);
+        let old = self.memory.peek(addr);
+        if let Some(history) = &mut self.history {
+            history.remember(addr, old);
+        }
+        self.memory.write(addr, value);
+        self.watch(addr, 1, AccessKind::Write, old as u16, value as u16);
+        self.observer.event(&Event::MemoryWrite {
+            addr,
+            size: 1,
+            value: value as u16,
+        });
     }
 
     /// Stores `value` into `addr`
-    fn store_16(&mut self, addr: u16, value: u16) {
+    fn store_16(&mut self, addr: u16, value: u16) -> Result<(), EmulatorError> {
+        self.check_range(addr, 2)?;
+        self.record(addr, 2, AccessKind::Write, AccessSource::Data);
+        let old = u16::from_be_bytes([self.memory.peek(addr), self.memory.peek(addr + 1)]);
+        if let Some(history) = &mut self.history {
+            let [high, low] = old.to_be_bytes();
+            history.remember(addr, high);
+            history.remember(addr + 1, low);
+        }
         let bytes = value.to_be_bytes();
-        println!("storing 16 bits: {value:X} to [{addr:X}]");
-        self.memory[addr as usize + 0] = bytes[0];
-        self.memory[addr as usize + 1] = bytes[1];
+        self.memory.write(addr, bytes[0]);
+        self.memory.write(addr + 1, bytes[1]);
+        self.watch(addr, 2, AccessKind::Write, old, value);
+        self.observer.event(&Event::MemoryWrite {
+            addr,
+            size: 2,
+            value,
+        });
+        Ok(())
     }
 
-    fn execute_instruction(&mut self) -> Result<ExecuteResult, ()> {
-        println!();
-        println!("REGISTERS:");
-        println!("PC: 0x{:X}", self.pc);
-        println!("IR: 0x{:X}", self.ir);
-        println!("ACC: 0x{:X}", self.acc);
-        println!("MAR: 0x{:X}", self.mar);
-
+    fn execute_instruction(&mut self) -> Result<ExecuteResult, EmulatorError> {
+        let pc = self.instruction_pc;
         match try_parse(self.ir) {
             None => {
-                println!(
-                    "illegal instruction: 0b{:08b} at PC: {:X}",
-                    self.ir, self.pc
-                );
-                return Err(());
+                self.observer.event(&Event::IllegalInstruction {
+                    pc,
+                    opcode: self.ir,
+                });
+                // Loads and stores only define three of the four addressing modes
+                let opcode = self.ir;
+                return Err(if opcode & 0b1111_0011 == 0b0000_0011 {
+                    EmulatorError::UnsupportedAddressingMode { opcode, pc }
+                } else {
+                    EmulatorError::IllegalOpcode { opcode, pc }
+                });
             }
             Some(ins) => {
-                dbg!(&ins);
+                // The operands have not been fetched yet, so they are peeked for the event
+                let mut bytes = [0; MAX_INSTRUCTION_SIZE];
+                bytes[0] = self.ir;
+                for (offset, byte) in (1..).zip(&mut bytes[1..=operand_widths(&ins).iter().sum()]) {
+                    *byte = self.peek(pc.wrapping_add(offset));
+                }
+                self.instruction_bytes = bytes;
+                self.observer.event(&Event::Decoded {
+                    pc,
+                    instruction: ins.clone(),
+                    bytes,
+                });
+                self.instructions += 1;
+                self.execute_cycles += self.costs.cost(&ins);
                 match ins {
                     Instruction::Mathmatical { func, src, dst } => {
-                        // Holds the address we most recently fetched from
+                        // MAR is the only 16 bit destination, so operands are only 16 bits wide
+                        // when it is the destination
+                        let wide = dst == DstTarget::Mar;
                         let a = match src {
+                            SrcTarget::Indirect if wide => self.fetch_16(self.mar)?,
                             SrcTarget::Indirect => self.fetch_8(self.mar) as u16,
                             SrcTarget::Acc => self.acc as u16,
-                            SrcTarget::Constant => match dst {
-                                // We are storing to an 8 bit register, so only load 16 bits if we
-                                // store 16 bits
-                                DstTarget::Acc => self.fetch_8_pc() as u16,
-                                _ => self.fetch_16_pc(),
-                            },
+                            SrcTarget::Constant if wide => self.fetch_16_pc()?,
+                            SrcTarget::Constant => self.fetch_8_pc()? as u16,
                             SrcTarget::Memory => {
-                                let addr = self.fetch_16_pc();
-                                self.fetch_16(addr)
+                                let addr = self.fetch_16_pc()?;
+                                if wide {
+                                    self.fetch_16(addr)?
+                                } else {
+                                    self.fetch_8(addr) as u16
+                                }
                             }
                         };
                         // Gets the second opperand for math as well as an address for write back
@@ -142,130 +763,277 @@ impl Computer {
                         let (b, addr) = match dst {
                             DstTarget::Indirect => (self.fetch_8(self.mar) as u16, Some(self.mar)),
                             DstTarget::Acc => (self.acc as u16, None),
-                            DstTarget::Mar => (self.mar as u16, None),
+                            DstTarget::Mar => (self.mar, None),
                             DstTarget::Memory => {
-                                let addr = self.fetch_16_pc();
-                                (self.fetch_16(addr), Some(addr))
+                                let addr = self.fetch_16_pc()?;
+                                (self.fetch_8(addr) as u16, Some(addr))
                             }
                         };
-                        dbg!(a, b, addr);
+                        // The destination is the left hand side, so `Sub` computes dst - src.
+                        // `Inc`, `Dec` and `Not` only operate on the destination
                         let result = match func {
-                            MathFunction::And => a & b,
-                            MathFunction::Or => a | b,
-                            MathFunction::Xor => a ^ b,
-                            MathFunction::Add => a.wrapping_add(b),
-                            MathFunction::Sub => a.wrapping_sub(b),
-                            MathFunction::Inc => a.wrapping_add(1),
-                            MathFunction::Dec => a.wrapping_sub(1),
-                            MathFunction::Not => !a,
+                            MathFunction::And => b & a,
+                            MathFunction::Or => b | a,
+                            MathFunction::Xor => b ^ a,
+                            MathFunction::Add => b.wrapping_add(a),
+                            MathFunction::Sub => b.wrapping_sub(a),
+                            MathFunction::Inc => b.wrapping_add(1),
+                            MathFunction::Dec => b.wrapping_sub(1),
+                            MathFunction::Not => !b,
                         };
-                        dbg!(result);
                         match dst {
-                            DstTarget::Indirect => {
-                                self.store_8(addr.unwrap(), result as u8);
-                                panic!();
-                            }
-                            DstTarget::Acc => {
-                                self.acc = result as u8;
-                                println!("storing {} into ACC", self.acc);
-                            }
-                            DstTarget::Mar => {
-                                self.mar = result as u16;
-                                println!("storing {} into MAR", self.mar);
-                            }
-                            DstTarget::Memory => {
-                                panic!();
-                                self.store_16(addr.unwrap(), result);
+                            DstTarget::Indirect | DstTarget::Memory => {
+                                self.store_8(addr.unwrap(), result as u8)
                             }
+                            DstTarget::Acc => self.set_acc(result as u8),
+                            DstTarget::Mar => self.set_mar(result),
                         }
                     }
                     Instruction::Load { dst, src } => {
                         match src {
                             MemoryMethod::Address => {
-                                let addr = dbg!(self.fetch_16_pc());
+                                let addr = self.fetch_16_pc()?;
                                 match dst {
-                                    Register::Acc => self.acc = dbg!(self.fetch_8(addr)),
-                                    Register::Mar => self.mar = dbg!(self.fetch_16(addr)),
+                                    Register::Acc => {
+                                        let value = self.fetch_8(addr);
+                                        self.set_acc(value)
+                                    }
+                                    Register::Mar => {
+                                        let value = self.fetch_16(addr)?;
+                                        self.set_mar(value)
+                                    }
                                 }
                             }
                             MemoryMethod::Constant => match dst {
-                                Register::Acc => self.acc = dbg!(self.fetch_8_pc()),
-                                Register::Mar => self.mar = dbg!(self.fetch_16_pc()),
+                                Register::Acc => {
+                                    let value = self.fetch_8_pc()?;
+                                    self.set_acc(value)
+                                }
+                                Register::Mar => {
+                                    let value = self.fetch_16_pc()?;
+                                    self.set_mar(value)
+                                }
                             },
                             MemoryMethod::Indirect => match dst {
-                                Register::Acc => self.acc = dbg!(self.fetch_8(self.mar)),
-                                Register::Mar => self.mar = dbg!(self.fetch_16(self.mar)),
+                                Register::Acc => {
+                                    let value = self.fetch_8(self.mar);
+                                    self.set_acc(value)
+                                }
+                                Register::Mar => {
+                                    let value = self.fetch_16(self.mar)?;
+                                    self.set_mar(value)
+                                }
                             },
                         };
                     }
                     Instruction::Store { src, dst } => {
-                        dbg!(src, dst);
                         match dst {
                             MemoryMethod::Address => {
-                                let addr = dbg!(self.fetch_16_pc());
+                                let addr = self.fetch_16_pc()?;
                                 match src {
-                                    Register::Acc => self.store_8(addr, self.acc as u8),
-                                    Register::Mar => self.store_16(addr, self.mar),
+                                    Register::Acc => self.store_8(addr, self.acc),
+                                    Register::Mar => self.store_16(addr, self.mar)?,
                                 }
                             }
                             MemoryMethod::Constant => {
-                                let addr = self.fetch_16_pc();
+                                let addr = self.fetch_16_pc()?;
                                 match src {
-                                    Register::Acc => self.store_8(addr, self.acc as u8),
-                                    Register::Mar => self.store_16(addr, self.mar),
+                                    Register::Acc => self.store_8(addr, self.acc),
+                                    Register::Mar => self.store_16(addr, self.mar)?,
                                 }
                             }
                             MemoryMethod::Indirect => match src {
-                                Register::Acc => self.store_8(self.mar, self.acc as u8),
-                                Register::Mar => self.store_16(self.mar, self.mar),
+                                Register::Acc => self.store_8(self.mar, self.acc),
+                                Register::Mar => self.store_16(self.mar, self.mar)?,
                             },
                         };
                     }
                     Instruction::Branch(kind) => {
-                        let jmp_addr = self.fetch_16_pc();
+                        let jmp_addr = self.fetch_16_pc()?;
                         match kind {
                             BranchKind::Bra => {
-                                self.pc = jmp_addr;
+                                self.set_pc(jmp_addr);
                             }
                             BranchKind::Brz => {
                                 if self.acc == 0 {
-                                    self.pc = jmp_addr;
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                             BranchKind::Bne => {
                                 if self.acc != 0 {
-                                    self.pc = jmp_addr;
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                             BranchKind::Blt => {
-                                if self.acc < 0 {
-                                    self.pc = jmp_addr;
+                                if (self.acc as i8) < 0 {
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                             BranchKind::Ble => {
-                                if self.acc <= 0 {
-                                    self.pc = jmp_addr;
+                                if (self.acc as i8) <= 0 {
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                             BranchKind::Bgt => {
-                                if self.acc > 0 {
-                                    self.pc = jmp_addr;
+                                if (self.acc as i8) > 0 {
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                             BranchKind::Bge => {
-                                if self.acc >= 0 {
-                                    self.pc = jmp_addr;
+                                if (self.acc as i8) >= 0 {
+                                    self.set_pc(jmp_addr);
                                 }
                             }
                         }
                     }
                     Instruction::Nop => {}
                     Instruction::Hault => {
+                        self.observer.event(&Event::Halted { pc });
                         return Ok(ExecuteResult::Hault);
                     }
                 }
             }
         }
-        return Ok(ExecuteResult::Continue);
+        Ok(ExecuteResult::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_mem_out() {
+        let mut memory = [0u8; MEMORY_SIZE];
+        let initial_memory = include!("../mem_in.txt");
+        memory[..initial_memory.len()].copy_from_slice(&initial_memory);
+        let mut computer = Computer::with_cache(memory, CacheConfig::default());
+        assert_eq!(computer.run(), Ok(StopReason::Halted));
+
+        let expected_memory = include!("../mem_out.txt");
+        assert_eq!(
+            &computer.memory()[..expected_memory.len()],
+            &expected_memory[..]
+        );
+    }
+
+    #[test]
+    fn math_and_branch_semantics() {
+        let mut memory = [0u8; MEMORY_SIZE];
+        // LOAD ACC #0x32, SUB ACC #0x1E, INC ACC, STORE ACC 0x2000, LOAD MAR #0x1234,
+        // ADD MAR #0x0101, STORE MAR 0x2001, LOAD ACC #0x80, BLT 0x0019, LOAD ACC #0x00, NOP,
+        // STORE ACC 0x2003, HALT
+        memory[..29].copy_from_slice(&[
+            0x09, 0x32, 0xC6, 0x1E, 0xD5, 0x00, 0x20, 0x00, 0x0D, 0x12, 0x34, 0xBA, 0x01, 0x01,
+            0x04, 0x20, 0x01, 0x09, 0x80, 0x13, 0x00, 0x19, 0x09, 0x00, 0x18, 0x00, 0x20, 0x03,
+            0x19,
+        ]);
+        let mut computer = Computer::new(memory);
+        assert_eq!(computer.run(), Ok(StopReason::Halted));
+        assert_eq!(
+            &computer.memory()[0x2000..0x2004],
+            &[0x15, 0x13, 0x35, 0x80]
+        );
+    }
+
+    /// LOAD ACC #3, then DEC ACC and BNE back to it until ACC is 0, then HALT
+    fn countdown_memory() -> [u8; MEMORY_SIZE] {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..7].copy_from_slice(&[0x09, 0x03, 0xE5, 0x12, 0x00, 0x02, 0x19]);
+        memory
+    }
+
+    fn countdown() -> Computer {
+        Computer::new(countdown_memory())
+    }
+
+    #[test]
+    fn steps() {
+        let mut computer = countdown();
+        assert_eq!(computer.step(), Ok(ExecuteResult::Continue));
+        assert_eq!(computer.timing().instructions, 1);
+        assert_eq!(computer.run_for(100), Ok(StopReason::Halted));
+        assert!(computer.halted());
+        assert_eq!(computer.timing().instructions, 8);
+        assert_eq!(computer.step(), Ok(ExecuteResult::Hault));
+        assert_eq!(computer.timing().instructions, 8);
+    }
+
+    #[test]
+    fn stops_at_limits() {
+        let mut computer = countdown();
+        assert_eq!(computer.run_for(3), Ok(StopReason::InstructionLimit));
+        assert_eq!(computer.timing().instructions, 3);
+
+        // Every instruction takes over 100 cycles without a cache
+        assert_eq!(computer.run_cycles(100), Ok(StopReason::CycleLimit));
+        assert_eq!(computer.timing().instructions, 4);
+
+        assert_eq!(
+            computer.run_until(|computer| computer.timing().instructions == 6),
+            Ok(StopReason::Condition)
+        );
+        assert_eq!(computer.timing().instructions, 6);
+        assert_eq!(computer.run_cycles(u64::MAX), Ok(StopReason::Halted));
+    }
+
+    #[test]
+    fn bounds_infinite_loops() {
+        let mut memory = [0; MEMORY_SIZE];
+        // BRA 0x0000
+        memory[..3].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let mut computer = Computer::new(memory);
+        assert_eq!(computer.run_for(1000), Ok(StopReason::InstructionLimit));
+        assert!(!computer.halted());
+    }
+
+    #[test]
+    fn starts_from_state() {
+        let mut memory = [0; MEMORY_SIZE];
+        // ADD ACC, [MAR]
+        memory[0x100] = 0b1011_0100;
+        memory[0x2000] = 0x05;
+        let state = CpuState {
+            acc: 0x10,
+            mar: 0x2000,
+            ..CpuState::new(0x100)
+        };
+        let mut computer = Computer::with_state(memory, state);
+        assert_eq!(computer.state(), state);
+        assert_eq!(computer.step(), Ok(ExecuteResult::Continue));
+        assert_eq!(
+            computer.state(),
+            CpuState {
+                pc: 0x101,
+                ir: 0b1011_0100,
+                acc: 0x15,
+                mar: 0x2000,
+            }
+        );
+    }
+
+    #[test]
+    fn changes_registers_and_memory() {
+        let mut computer = Computer::with_cache(countdown_memory(), CacheConfig::default());
+        assert_eq!(computer.run_for(2), Ok(StopReason::InstructionLimit));
+        assert_eq!(computer.acc(), 2);
+
+        // Restart the countdown from 1
+        computer.set_acc(1);
+        assert_eq!(computer.run(), Ok(StopReason::Halted));
+        assert_eq!(computer.timing().instructions, 6);
+
+        // Rerun with a longer countdown, without waiting for the cache to be flushed
+        computer.poke(0x0001, 4);
+        assert_eq!(computer.peek(0x0001), 4);
+        computer.set_pc(0);
+        assert!(!computer.halted());
+        computer.run().unwrap();
+        assert_eq!(computer.timing().instructions, 16);
+
+        computer.memory_mut()[0x0001] = 1;
+        computer.set_pc(0);
+        computer.run().unwrap();
+        assert_eq!(computer.timing().instructions, 20);
     }
 }
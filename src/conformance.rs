@@ -0,0 +1,394 @@
+//! Instruction set conformance tests. Every opcode [`try_parse`] accepts is executed once from a
+//! known machine state, checking the registers, pc and every byte of memory afterwards.
+//!
+//! Cases are rows in [`cases`], so new ones only need the instruction's bytes and what it should
+//! change. Math instructions are generated from tables of their sources, destinations and
+//! functions instead, since all 128 opcodes are legal
+
+use crate::*;
+
+/// Where the instruction under test is placed
+const CODE: u16 = 0x0100;
+
+/// Registers every case starts with unless it says otherwise
+const ACC: u8 = 0x35;
+const MAR: u16 = 0x2000;
+
+/// Memory every case starts with, besides its code. MAR points at the first pair, the others are
+/// used as direct addresses
+const DATA: [(u16, [u8; 2]); 3] = [
+    (0x2000, [0x12, 0x34]),
+    (0x3000, [0x56, 0x78]),
+    (0x4000, [0x9A, 0xBC]),
+];
+
+struct Case {
+    /// The instruction, opcode first, followed by any operands
+    code: Vec<u8>,
+    acc: u8,
+    mar: u16,
+    /// Expected registers afterwards
+    pc: u16,
+    acc_after: u8,
+    mar_after: u16,
+    halts: bool,
+    /// Every byte the instruction should change
+    writes: Vec<(u16, u8)>,
+}
+
+/// A case for `code` that expects pc to move past it and nothing else to change
+fn case(code: &[u8]) -> Case {
+    Case {
+        code: code.to_vec(),
+        acc: ACC,
+        mar: MAR,
+        pc: CODE + code.len() as u16,
+        acc_after: ACC,
+        mar_after: MAR,
+        halts: false,
+        writes: vec![],
+    }
+}
+
+/// Memory as a case starts, without its code
+fn fixture() -> [u8; MEMORY_SIZE] {
+    let mut memory = [0; MEMORY_SIZE];
+    for (addr, bytes) in DATA {
+        memory[addr as usize..addr as usize + 2].copy_from_slice(&bytes);
+    }
+    memory
+}
+
+/// Loads, stores, branches, nop and halt, along with math edge cases that the generated math
+/// cases don't reach
+fn cases() -> Vec<Case> {
+    let mut cases = vec![
+        // LOAD ACC, [0x3000]
+        Case {
+            acc_after: 0x56,
+            ..case(&[0x08, 0x30, 0x00])
+        },
+        // LOAD ACC, #0xC3
+        Case {
+            acc_after: 0xC3,
+            ..case(&[0x09, 0xC3])
+        },
+        // LOAD ACC, [MAR]
+        Case {
+            acc_after: 0x12,
+            ..case(&[0x0A])
+        },
+        // LOAD MAR, [0x3000]
+        Case {
+            mar_after: 0x5678,
+            ..case(&[0x0C, 0x30, 0x00])
+        },
+        // LOAD MAR, #0xC3D4
+        Case {
+            mar_after: 0xC3D4,
+            ..case(&[0x0D, 0xC3, 0xD4])
+        },
+        // LOAD MAR, [MAR]
+        Case {
+            mar_after: 0x1234,
+            ..case(&[0x0E])
+        },
+        // STORE ACC, [0x3000]
+        Case {
+            writes: vec![(0x3000, ACC)],
+            ..case(&[0x00, 0x30, 0x00])
+        },
+        // The constant mode of a store uses its operand as the address to store to
+        Case {
+            writes: vec![(0x4001, ACC)],
+            ..case(&[0x01, 0x40, 0x01])
+        },
+        // STORE ACC, [MAR]
+        Case {
+            writes: vec![(0x2000, ACC)],
+            ..case(&[0x02])
+        },
+        // STORE MAR, [0x3000]
+        Case {
+            writes: vec![(0x3000, 0x20), (0x3001, 0x00)],
+            ..case(&[0x04, 0x30, 0x00])
+        },
+        Case {
+            writes: vec![(0x4001, 0x20), (0x4002, 0x00)],
+            ..case(&[0x05, 0x40, 0x01])
+        },
+        // STORE MAR, [MAR]
+        Case {
+            writes: vec![(0x2000, 0x20), (0x2001, 0x00)],
+            ..case(&[0x06])
+        },
+        // NOP
+        case(&[0x18]),
+        // HALT
+        Case {
+            halts: true,
+            ..case(&[0x19])
+        },
+        // ADD ACC, #0x01 carries out of the top of ACC
+        Case {
+            acc: 0xFF,
+            acc_after: 0x00,
+            ..case(&[0xB6, 0x01])
+        },
+        // INC MAR carries between its bytes and out of the top
+        Case {
+            mar: 0x00FF,
+            mar_after: 0x0100,
+            ..case(&[0xD9])
+        },
+        Case {
+            mar: 0xFFFF,
+            mar_after: 0x0000,
+            ..case(&[0xD9])
+        },
+        // SUB [0x4000], #0x9B borrows
+        Case {
+            writes: vec![(0x4000, 0xFF)],
+            ..case(&[0xCE, 0x9B, 0x40, 0x00])
+        },
+    ];
+
+    // Whether each branch is taken when ACC is zero, positive and negative
+    let branches = [
+        (0x10, [true, true, true]),
+        (0x11, [true, false, false]),
+        (0x12, [false, true, true]),
+        (0x13, [false, false, true]),
+        (0x14, [true, false, true]),
+        (0x15, [false, true, false]),
+        (0x16, [true, true, false]),
+    ];
+    for (opcode, taken) in branches {
+        for (acc, taken) in [0x00, 0x7F, 0x80].into_iter().zip(taken) {
+            let mut case = Case {
+                acc,
+                acc_after: acc,
+                ..case(&[opcode, 0x40, 0x00])
+            };
+            if taken {
+                case.pc = 0x4000;
+            }
+            cases.push(case);
+        }
+    }
+
+    cases.extend(math_cases());
+    cases
+}
+
+/// A math instruction's source operand
+struct Source {
+    bits: u8,
+    /// Operand bytes following the opcode when the operand is 8 and 16 bits wide
+    narrow: &'static [u8],
+    wide: &'static [u8],
+    /// The operand's value when it is 8 and 16 bits wide
+    narrow_value: u8,
+    wide_value: u16,
+}
+
+/// A math instruction's destination, which is also its left hand operand
+struct Destination {
+    bits: u8,
+    /// Operand bytes following any source operand
+    operand: &'static [u8],
+    value: u16,
+    /// Where the result is stored, if not in a register
+    addr: Option<u16>,
+}
+
+const SOURCES: [Source; 4] = [
+    // [MAR]
+    Source {
+        bits: 0b00,
+        narrow: &[],
+        wide: &[],
+        narrow_value: 0x12,
+        wide_value: 0x1234,
+    },
+    // ACC
+    Source {
+        bits: 0b01,
+        narrow: &[],
+        wide: &[],
+        narrow_value: ACC,
+        wide_value: ACC as u16,
+    },
+    // #0xC3 or #0xC3D4
+    Source {
+        bits: 0b10,
+        narrow: &[0xC3],
+        wide: &[0xC3, 0xD4],
+        narrow_value: 0xC3,
+        wide_value: 0xC3D4,
+    },
+    // [0x3000]
+    Source {
+        bits: 0b11,
+        narrow: &[0x30, 0x00],
+        wide: &[0x30, 0x00],
+        narrow_value: 0x56,
+        wide_value: 0x5678,
+    },
+];
+
+const DESTINATIONS: [Destination; 4] = [
+    // [MAR]
+    Destination {
+        bits: 0b00,
+        operand: &[],
+        value: 0x12,
+        addr: Some(MAR),
+    },
+    // ACC
+    Destination {
+        bits: 0b01,
+        operand: &[],
+        value: ACC as u16,
+        addr: None,
+    },
+    // MAR
+    Destination {
+        bits: 0b10,
+        operand: &[],
+        value: MAR,
+        addr: None,
+    },
+    // [0x4000]
+    Destination {
+        bits: 0b11,
+        operand: &[0x40, 0x00],
+        value: 0x9A,
+        addr: Some(0x4000),
+    },
+];
+
+/// What each math function computes from its destination and source, in opcode order
+const FUNCTIONS: [fn(u16, u16) -> u16; 8] = [
+    |dst, src| dst & src,
+    |dst, src| dst | src,
+    |dst, src| dst ^ src,
+    u16::wrapping_add,
+    u16::wrapping_sub,
+    |dst, _| dst.wrapping_add(1),
+    |dst, _| dst.wrapping_sub(1),
+    |dst, _| !dst,
+];
+
+/// One case for every math opcode. Operands are 16 bits wide exactly when MAR is the
+/// destination
+fn math_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for (func, apply) in FUNCTIONS.iter().enumerate() {
+        for dst in &DESTINATIONS {
+            for src in &SOURCES {
+                let wide = dst.bits == 0b10;
+                let (src_operand, src_value) = match wide {
+                    true => (src.wide, src.wide_value),
+                    false => (src.narrow, src.narrow_value as u16),
+                };
+                let mut code = vec![0x80 | (func as u8) << 4 | dst.bits << 2 | src.bits];
+                code.extend(src_operand);
+                code.extend(dst.operand);
+                let mut case = case(&code);
+                let result = apply(dst.value, src_value);
+                match (dst.addr, wide) {
+                    (Some(addr), _) => case.writes.push((addr, result as u8)),
+                    (None, true) => case.mar_after = result,
+                    (None, false) => case.acc_after = result as u8,
+                }
+                cases.push(case);
+            }
+        }
+    }
+    cases
+}
+
+/// Runs `case` on `computer`, which holds the fixture and the case's code
+fn check(case: &Case, mut computer: Computer) {
+    let name = disassemble_one(&case.code, CODE).unwrap().text;
+    computer.set_state(CpuState {
+        pc: CODE,
+        ir: 0,
+        acc: case.acc,
+        mar: case.mar,
+    });
+    let expected_result = match case.halts {
+        true => ExecuteResult::Hault,
+        false => ExecuteResult::Continue,
+    };
+    assert_eq!(computer.step(), Ok(expected_result), "{name}");
+    computer.flush();
+
+    let expected = CpuState {
+        pc: case.pc,
+        ir: case.code[0],
+        acc: case.acc_after,
+        mar: case.mar_after,
+    };
+    assert_eq!(computer.state(), expected, "{name}");
+    assert_eq!(computer.halted(), case.halts, "{name}");
+    assert_eq!(computer.timing().instructions, 1, "{name}");
+
+    let mut memory = fixture();
+    memory[CODE as usize..][..case.code.len()].copy_from_slice(&case.code);
+    for &(addr, value) in &case.writes {
+        memory[addr as usize] = value;
+    }
+    let differences = diff_memory(&memory, computer.memory());
+    assert!(
+        differences.is_empty(),
+        "{name} changed memory at {differences:X?}"
+    );
+}
+
+#[test]
+fn executes_every_opcode() {
+    for case in cases() {
+        let mut memory = fixture();
+        memory[CODE as usize..][..case.code.len()].copy_from_slice(&case.code);
+        check(&case, Computer::new(memory));
+        // Going through a write back cache must not change what the instruction does
+        check(&case, Computer::with_cache(memory, CacheConfig::default()));
+    }
+}
+
+#[test]
+fn covers_every_opcode() {
+    let cases = cases();
+    for opcode in 0..=u8::MAX {
+        let covered = cases.iter().any(|case| case.code[0] == opcode);
+        assert_eq!(
+            covered,
+            try_parse(opcode).is_some(),
+            "opcode 0x{opcode:02X} is {}",
+            if covered {
+                "covered but illegal"
+            } else {
+                "not covered"
+            }
+        );
+    }
+}
+
+#[test]
+fn rejects_illegal_opcodes() {
+    for opcode in (0..=u8::MAX).filter(|&opcode| try_parse(opcode).is_none()) {
+        let mut memory = fixture();
+        memory[CODE as usize] = opcode;
+        let mut computer = Computer::with_state(memory, CpuState::new(CODE));
+        let pc = CODE;
+        let expected = match opcode & 0b1111_0011 {
+            0b0000_0011 => EmulatorError::UnsupportedAddressingMode { opcode, pc },
+            _ => EmulatorError::IllegalOpcode { opcode, pc },
+        };
+        assert_eq!(computer.step(), Err(expected));
+        assert_eq!(computer.timing().instructions, 0);
+        assert!(diff_memory(&memory, computer.memory()).is_empty());
+    }
+}
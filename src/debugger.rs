@@ -0,0 +1,652 @@
+//! An interactive debugger for stepping through programs and inspecting the cpu
+
+use crate::history::DEBUG_HISTORY_LIMIT;
+use crate::{
+    disassemble_one, read_snapshot, write_snapshot, Computer, CpuRegister, EmulatorError,
+    StopReason, MAX_INSTRUCTION_SIZE, MEMORY_SIZE,
+};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "commands:
+  break <addr>            stop before executing the instruction at addr
+  delete <addr>           remove a breakpoint
+  breakpoints             list breakpoints
+  watch <watchpoint>      stop when data in a range is accessed, as <addr>[-<addr>][:r|w|rw][=<value>]
+  unwatch <index>         remove a watchpoint
+  watchpoints             list watchpoints
+  step [count]            execute count instructions, 1 by default
+  continue [count]        run until a breakpoint, a halt or a fault, for at most count
+                          instructions, 10000000 by default
+  back [count]            undo count instructions, 1 by default
+  rewind <instructions>   undo instructions until only this many have executed
+  regs                    print PC, IR, ACC and MAR
+  set <register> <value>  change PC, IR, ACC or MAR
+  x <addr> [len]          hexdump len bytes starting at addr, 16 by default
+  write <addr> <byte>...  change memory starting at addr
+  disas [count]           disassemble count instructions starting at PC, 1 by default
+  save <file>             write a snapshot of the whole machine to file
+  load <file>             replace the machine with a snapshot, keeping breakpoints and watchpoints
+  help                    show this message
+  quit
+numbers are hex with 0x, binary with 0b or decimal. Commands can be shortened to their first
+letter, except delete and disas which are `d` and `l`";
+
+/// How many instructions `continue` runs by default before giving control back, so a program
+/// that never reaches a breakpoint or halts doesn't hang the debugger
+pub(crate) const CONTINUE_LIMIT: u64 = 10_000_000;
+
+/// Why a command could not be run
+enum CommandError {
+    /// The command was mistyped, or asked for something impossible
+    Mistake(String),
+    /// Writing the response failed
+    Io(io::Error),
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Mistake(message)
+    }
+}
+
+impl From<&str> for CommandError {
+    fn from(message: &str) -> Self {
+        CommandError::Mistake(message.to_owned())
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
+
+/// Wraps a [`Computer`], running commands typed at a prompt
+pub struct Debugger {
+    computer: Computer,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl Debugger {
+    /// Debugs `computer`, recording its history so that it can step backwards
+    pub fn new(mut computer: Computer) -> Self {
+        computer.record_history(DEBUG_HISTORY_LIMIT);
+        Self {
+            computer,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn computer(&self) -> &Computer {
+        &self.computer
+    }
+
+    pub fn computer_mut(&mut self) -> &mut Computer {
+        &mut self.computer
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is entered, writing a prompt before
+    /// each one
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        self.disassemble(1, &mut out)?;
+        write!(out, "(dbg) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut out)? {
+                return Ok(());
+            }
+            write!(out, "(dbg) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// Runs a single command, returning false if it was `quit`. Mistakes in the command are
+    /// reported to `out` rather than returned
+    pub fn command(&mut self, line: &str, mut out: impl Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let result = match name {
+            "quit" | "q" => return Ok(false),
+            "break" | "b" => self.set_breakpoint(args, &mut out),
+            "delete" | "d" => self.delete_breakpoint(args, &mut out),
+            "breakpoints" => self.list_breakpoints(args, &mut out),
+            "watch" => self.set_watchpoint(args, &mut out),
+            "unwatch" => self.delete_watchpoint(args, &mut out),
+            "watchpoints" => self.list_watchpoints(args, &mut out),
+            "step" | "s" => self.step(args, &mut out),
+            "continue" | "c" => self.cont(args, &mut out),
+            "back" => self.back(args, &mut out),
+            "rewind" => self.rewind(args, &mut out),
+            "regs" | "r" => self.registers(args, &mut out),
+            "set" => self.set_register(args, &mut out),
+            "x" => self.hexdump(args, &mut out),
+            "write" | "w" => self.write_memory(args, &mut out),
+            "save" => self.save(args, &mut out),
+            "load" => self.load(args, &mut out),
+            "disas" | "l" => self.list(args, &mut out),
+            "help" | "h" => writeln!(out, "{HELP}").map_err(CommandError::from),
+            _ => Err(format!("unknown command `{name}`, try `help`").into()),
+        };
+        match result {
+            Ok(()) => {}
+            Err(CommandError::Mistake(message)) => writeln!(out, "error: {message}")?,
+            Err(CommandError::Io(err)) => return Err(err),
+        }
+        Ok(true)
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let [addr] = args else {
+            return Err("usage: break <addr>".into());
+        };
+        let addr = parse_u16(addr)?;
+        self.breakpoints.insert(addr);
+        Ok(writeln!(out, "breakpoint at 0x{addr:04X}")?)
+    }
+
+    fn delete_breakpoint(
+        &mut self,
+        args: &[&str],
+        mut out: impl Write,
+    ) -> Result<(), CommandError> {
+        let [addr] = args else {
+            return Err("usage: delete <addr>".into());
+        };
+        let addr = parse_u16(addr)?;
+        if !self.breakpoints.remove(&addr) {
+            return Err(format!("no breakpoint at 0x{addr:04X}").into());
+        }
+        Ok(writeln!(out, "deleted breakpoint at 0x{addr:04X}")?)
+    }
+
+    fn list_breakpoints(&self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        if !args.is_empty() {
+            return Err("usage: breakpoints".into());
+        }
+        if self.breakpoints.is_empty() {
+            writeln!(out, "no breakpoints")?;
+        }
+        for addr in &self.breakpoints {
+            writeln!(out, "0x{addr:04X}")?;
+        }
+        Ok(())
+    }
+
+    fn set_watchpoint(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let [watchpoint] = args else {
+            return Err("usage: watch <addr>[-<addr>][:r|w|rw][=<value>]".into());
+        };
+        let index = self.computer.add_watchpoint(watchpoint.parse()?);
+        Ok(writeln!(out, "watchpoint {index}")?)
+    }
+
+    fn delete_watchpoint(
+        &mut self,
+        args: &[&str],
+        mut out: impl Write,
+    ) -> Result<(), CommandError> {
+        let [index] = args else {
+            return Err("usage: unwatch <index>".into());
+        };
+        let index = parse_number(index)?;
+        if index >= self.computer.watchpoints().len() {
+            return Err(format!("no watchpoint {index}").into());
+        }
+        self.computer.remove_watchpoint(index);
+        Ok(writeln!(out, "deleted watchpoint {index}")?)
+    }
+
+    fn list_watchpoints(&self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        if !args.is_empty() {
+            return Err("usage: watchpoints".into());
+        }
+        let watchpoints = self.computer.watchpoints();
+        if watchpoints.is_empty() {
+            writeln!(out, "no watchpoints")?;
+        }
+        for (index, watchpoint) in watchpoints.iter().enumerate() {
+            write!(
+                out,
+                "{index}: {:?} 0x{:04X}-0x{:04X}",
+                watchpoint.kind,
+                watchpoint.range.start(),
+                watchpoint.range.end()
+            )?;
+            match watchpoint.value {
+                Some(value) => writeln!(out, " = 0x{value:X}")?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let count = match args {
+            [] => 1,
+            [count] => parse_number(count)?,
+            _ => return Err("usage: step [count]".into()),
+        };
+        let result = self.computer.run_for(count as u64);
+        Ok(self.report(result, &mut out)?)
+    }
+
+    fn cont(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let limit = match args {
+            [] => CONTINUE_LIMIT,
+            [count] => parse_number(count)? as u64,
+            _ => return Err("usage: continue [count]".into()),
+        };
+        if limit == 0 {
+            return Err("continue needs a count of at least 1".into());
+        }
+        let breakpoints = &self.breakpoints;
+        let mut executed = 0;
+        let mut result = self.computer.run_until(|computer| {
+            executed += 1;
+            breakpoints.contains(&computer.pc()) || executed == limit
+        });
+        if result == Ok(StopReason::Condition) {
+            let pc = self.computer.pc();
+            if breakpoints.contains(&pc) {
+                writeln!(out, "breakpoint at 0x{pc:04X}")?;
+            } else {
+                writeln!(out, "stopped after {limit} instructions")?;
+                result = Ok(StopReason::InstructionLimit);
+            }
+        }
+        Ok(self.report(result, &mut out)?)
+    }
+
+    fn back(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let count = match args {
+            [] => 1,
+            [count] => parse_number(count)?,
+            _ => return Err("usage: back [count]".into()),
+        };
+        for _ in 0..count {
+            if !self.computer.step_back() {
+                writeln!(out, "reached the start of the history")?;
+                break;
+            }
+        }
+        Ok(self.disassemble(1, out)?)
+    }
+
+    fn rewind(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let [instructions] = args else {
+            return Err("usage: rewind <instructions>".into());
+        };
+        let instructions = parse_number(instructions)? as u64;
+        if instructions > self.computer.timing().instructions {
+            return Err(format!(
+                "only {} instructions have executed",
+                self.computer.timing().instructions
+            )
+            .into());
+        }
+        if !self.computer.rewind_to(instructions) {
+            writeln!(out, "reached the start of the history")?;
+        }
+        Ok(self.disassemble(1, out)?)
+    }
+
+    /// Says why execution stopped, then shows the next instruction
+    fn report(
+        &self,
+        result: Result<StopReason, EmulatorError>,
+        mut out: impl Write,
+    ) -> io::Result<()> {
+        match result {
+            Ok(StopReason::Halted) => writeln!(out, "halted")?,
+            Ok(StopReason::Watchpoint) => writeln!(out, "{}", self.computer.watch_hit().unwrap())?,
+            Ok(_) => {}
+            Err(err) => writeln!(out, "stopped: {err}")?,
+        }
+        self.disassemble(1, out)
+    }
+
+    fn registers(&self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        if !args.is_empty() {
+            return Err("usage: regs".into());
+        }
+        let state = self.computer.state();
+        Ok(writeln!(
+            out,
+            "PC  0x{:04X}\nIR  0x{:02X}\nACC 0x{:02X}\nMAR 0x{:04X}",
+            state.pc, state.ir, state.acc, state.mar
+        )?)
+    }
+
+    fn set_register(&mut self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let [register, value] = args else {
+            return Err("usage: set <register> <value>".into());
+        };
+        let register = match register.to_ascii_uppercase().as_str() {
+            "PC" => CpuRegister::Pc,
+            "IR" => CpuRegister::Ir,
+            "ACC" => CpuRegister::Acc,
+            "MAR" => CpuRegister::Mar,
+            _ => return Err(format!("unknown register `{register}`").into()),
+        };
+        match register {
+            CpuRegister::Pc => self.computer.set_pc(parse_u16(value)?),
+            CpuRegister::Ir => self.computer.set_ir(parse_u8(value)?),
+            CpuRegister::Acc => self.computer.set_acc(parse_u8(value)?),
+            CpuRegister::Mar => self.computer.set_mar(parse_u16(value)?),
+        }
+        self.registers(&[], &mut out)
+    }
+
+    fn hexdump(&self, args: &[&str], out: impl Write) -> Result<(), CommandError> {
+        let (addr, len) = match args {
+            [addr] => (parse_u16(addr)?, 16),
+            [addr, len] => (parse_u16(addr)?, parse_number(len)?),
+            _ => return Err("usage: x <addr> [len]".into()),
+        };
+        Ok(self.dump(addr, len, out)?)
+    }
+
+    /// Writes `len` bytes starting at `addr` as rows of hex followed by ascii
+    fn dump(&self, addr: u16, len: usize, mut out: impl Write) -> io::Result<()> {
+        let end = (addr as usize).saturating_add(len).min(MEMORY_SIZE);
+        for start in (addr as usize..end).step_by(16) {
+            let bytes: Vec<u8> = (start..end.min(start + 16))
+                .map(|addr| self.computer.peek(addr as u16))
+                .collect();
+            let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7E => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "{start:04X}  {:<47}  {ascii}", hex.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn write_memory(&mut self, args: &[&str], out: impl Write) -> Result<(), CommandError> {
+        let [addr, bytes @ ..] = args else {
+            return Err("usage: write <addr> <byte>...".into());
+        };
+        if bytes.is_empty() {
+            return Err("usage: write <addr> <byte>...".into());
+        }
+        let addr = parse_u16(addr)?;
+        let bytes = bytes
+            .iter()
+            .map(|byte| parse_u8(byte))
+            .collect::<Result<Vec<_>, _>>()?;
+        if addr as usize + bytes.len() > MEMORY_SIZE {
+            return Err("write runs past the end of memory".into());
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.computer.poke(addr + i as u16, byte);
+        }
+        Ok(self.dump(addr, bytes.len(), out)?)
+    }
+
+    fn save(&self, args: &[&str], mut out: impl Write) -> Result<(), CommandError> {
+        let [path] = args else {
+            return Err("usage: save <file>".into());
+        };
+        std::fs::File::create(path)
+            .and_then(|file| write_snapshot(&self.computer, io::BufWriter::new(file)))
+            .map_err(|err| format!("failed to save {path}: {err}"))?;
+        Ok(writeln!(out, "saved {path}")?)
+    }
+
+    /// Swaps in the machine from a snapshot. Its history starts afresh, since the undo log
+    /// is not part of a snapshot
+    fn load(&mut self, args: &[&str], out: impl Write) -> Result<(), CommandError> {
+        let [path] = args else {
+            return Err("usage: load <file>".into());
+        };
+        let mut computer = std::fs::File::open(path)
+            .and_then(|file| read_snapshot(io::BufReader::new(file)))
+            .map_err(|err| format!("failed to load {path}: {err}"))?;
+        for watchpoint in self.computer.watchpoints() {
+            computer.add_watchpoint(watchpoint.clone());
+        }
+        computer.record_history(DEBUG_HISTORY_LIMIT);
+        self.computer = computer;
+        Ok(self.disassemble(1, out)?)
+    }
+
+    fn list(&self, args: &[&str], out: impl Write) -> Result<(), CommandError> {
+        let count = match args {
+            [] => 1,
+            [count] => parse_number(count)?,
+            _ => return Err("usage: disas [count]".into()),
+        };
+        Ok(self.disassemble(count, out)?)
+    }
+
+    /// Lists `count` instructions starting at PC, marking the one at PC with `=>` and any with
+    /// breakpoints with `*`
+    fn disassemble(&self, count: usize, mut out: impl Write) -> io::Result<()> {
+        if self.computer.halted() {
+            return writeln!(out, "the cpu has halted, set PC to run again");
+        }
+        let mut addr = self.computer.pc() as usize;
+        for i in 0..count {
+            let bytes: Vec<u8> = (addr..(addr + MAX_INSTRUCTION_SIZE).min(MEMORY_SIZE))
+                .map(|addr| self.computer.peek(addr as u16))
+                .collect();
+            // Nothing is left to disassemble past the end of memory
+            let Some(line) = disassemble_one(&bytes, addr as u16) else {
+                break;
+            };
+            let marker = match (i, self.breakpoints.contains(&(addr as u16))) {
+                (0, true) => "*=>",
+                (0, false) => " =>",
+                (_, true) => "*  ",
+                (_, false) => "   ",
+            };
+            writeln!(out, "{marker} {line}")?;
+            addr += line.bytes.len();
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        usize::from_str_radix(bin, 2)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("bad number `{text}`"))
+}
+
+fn parse_u16(text: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(text)?).map_err(|_| format!("{text} does not fit in 16 bits"))
+}
+
+fn parse_u8(text: &str) -> Result<u8, String> {
+    u8::try_from(parse_number(text)?).map_err(|_| format!("{text} does not fit in 8 bits"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn debug(commands: &str) -> (Debugger, String) {
+        // LOAD ACC #3, DEC ACC, BNE 0x0002, HALT
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..7].copy_from_slice(&[0x09, 0x03, 0xE5, 0x12, 0x00, 0x02, 0x19]);
+        let mut debugger = Debugger::new(Computer::new(memory));
+        let mut out = vec![];
+        debugger.repl(commands.as_bytes(), &mut out).unwrap();
+        (debugger, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let (debugger, out) = debug("break 0x0003\nc\nregs\nc\nd 3\nc\nq\n");
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg) breakpoint at 0x0003
+(dbg) breakpoint at 0x0003
+*=> 0003  12 00 02  BNE 0x0002
+(dbg) PC  0x0003
+IR  0xE5
+ACC 0x02
+MAR 0x0000
+(dbg) breakpoint at 0x0003
+*=> 0003  12 00 02  BNE 0x0002
+(dbg) deleted breakpoint at 0x0003
+(dbg) halted
+the cpu has halted, set PC to run again
+(dbg) "
+        );
+        assert!(debugger.computer().halted());
+    }
+
+    #[test]
+    fn steps_and_modifies() {
+        let (debugger, out) = debug("s 2\nset acc 0x10\nw 0x2000 0x41 66\nx 0x2000 3\nl 2\n");
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg)  => 0003  12 00 02  BNE 0x0002
+(dbg) PC  0x0003
+IR  0xE5
+ACC 0x10
+MAR 0x0000
+(dbg) 2000  41 42                                            AB
+(dbg) 2000  41 42 00                                         AB.
+(dbg)  => 0003  12 00 02  BNE 0x0002
+    0006  19        HALT
+(dbg) \n"
+        );
+        assert_eq!(debugger.computer().peek(0x2001), 0x42);
+    }
+
+    #[test]
+    fn limits_continue_and_dumps() {
+        let (debugger, out) = debug("c 2\nc 0\nx 0xFFF8 0xFFFFFFFFFFFFFFFF\nq\n");
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg) stopped after 2 instructions
+ => 0003  12 00 02  BNE 0x0002
+(dbg) error: continue needs a count of at least 1
+(dbg) FFF8  00 00 00 00 00 00 00 00                          ........
+(dbg) "
+        );
+        assert_eq!(debugger.computer().timing().instructions, 2);
+    }
+
+    #[test]
+    fn lists_the_longest_instructions() {
+        let (_, out) = debug("w 0 0xCF 0x30 0x00 0x20 0x00\nl 2\n");
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg) 0000  CF 30 00 20 00                                   .0. .
+(dbg)  => 0000  CF 30 00 20 00  SUB [0x2000], [0x3000]
+    0005  02        STORE ACC, [MAR]
+(dbg) \n"
+        );
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        // Replace the HALT with STORE ACC [0x2000], HALT
+        let (_, out) = debug(
+            "w 6 0x00 0x20 0x00 0x19\nwatch 0x2000-0x20FF=0\nwatch 0x1000:rw\nwatchpoints\n\
+             unwatch 1\nc\nc\n",
+        );
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg) 0006  00 20 00 19                                      . ..
+(dbg) watchpoint 0
+(dbg) watchpoint 1
+(dbg) 0: Write 0x2000-0x20FF = 0x0
+1: Access 0x1000-0x1000
+(dbg) deleted watchpoint 1
+(dbg) watchpoint 0: STORE ACC, [0x2000] at 0x0006 wrote 8 bits: 0x0 -> 0x0 to [0x2000]
+ => 0009  19        HALT
+(dbg) halted
+the cpu has halted, set PC to run again
+(dbg) \n"
+        );
+    }
+
+    #[test]
+    fn steps_backwards() {
+        let (debugger, out) = debug("c\nback 2\nr\nrewind 1\nback 5\nrewind 9\n");
+        assert_eq!(
+            out,
+            " => 0000  09 03     LOAD ACC, #0x03
+(dbg) halted
+the cpu has halted, set PC to run again
+(dbg)  => 0003  12 00 02  BNE 0x0002
+(dbg) PC  0x0003
+IR  0xE5
+ACC 0x00
+MAR 0x0000
+(dbg)  => 0002  E5        DEC ACC
+(dbg) reached the start of the history
+ => 0000  09 03     LOAD ACC, #0x03
+(dbg) error: only 0 instructions have executed
+(dbg) \n"
+        );
+        assert_eq!(debugger.computer().timing().instructions, 0);
+    }
+
+    #[test]
+    fn saves_and_loads_snapshots() {
+        let path = std::env::temp_dir().join(format!("debugger-{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (debugger, out) = debug(&format!(
+            "s 2\nsave {path}\nc\nload {path}\nload /nonexistent/snapshot\n"
+        ));
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                " => 0000  09 03     LOAD ACC, #0x03
+(dbg)  => 0003  12 00 02  BNE 0x0002
+(dbg) saved {path}
+(dbg) halted
+the cpu has halted, set PC to run again
+(dbg)  => 0003  12 00 02  BNE 0x0002
+(dbg) error: failed to load /nonexistent/snapshot: No such file or directory (os error 2)
+(dbg) \n"
+            )
+        );
+        assert_eq!(debugger.computer().acc(), 2);
+        assert_eq!(debugger.computer().timing().instructions, 2);
+    }
+
+    #[test]
+    fn reports_mistakes() {
+        let (_, out) = debug("jump\nbreak\nset sp 1\nset acc 0x100\n");
+        let errors: Vec<_> = out.lines().filter(|line| line.contains("error")).collect();
+        assert_eq!(
+            errors,
+            [
+                "(dbg) error: unknown command `jump`, try `help`",
+                "(dbg) error: usage: break <addr>",
+                "(dbg) error: unknown register `sp`",
+                "(dbg) error: 0x100 does not fit in 8 bits",
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Comparing memory against an expected image, reporting differences as side by side hexdumps
+
+use crate::disassemble_one;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// Number of bytes in each hexdump row
+const ROW_SIZE: usize = 16;
+
+/// The runs of consecutive addresses where `actual` differs from `expected`. Only addresses
+/// inside `expected` are compared
+pub fn diff_memory(expected: &[u8], actual: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for addr in (0..expected.len()).filter(|&addr| expected[addr] != actual[addr]) {
+        match ranges.last_mut() {
+            Some(range) if range.end == addr => range.end += 1,
+            _ => ranges.push(addr..addr + 1),
+        }
+    }
+    ranges
+}
+
+/// Writes every difference between `expected` and `actual` as a hexdump of the rows around it,
+/// with rows holding differences marked by `*`. Each differing byte is then listed along with
+/// the instruction `last_writer` says wrote it, disassembled from `actual`
+pub fn write_memory_diff(
+    expected: &[u8],
+    actual: &[u8],
+    last_writer: impl Fn(u16) -> Option<u16>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let ranges = diff_memory(expected, actual);
+    let bytes: usize = ranges.iter().map(ExactSizeIterator::len).sum();
+    writeln!(
+        out,
+        "{bytes} byte{} differ{} in {} range{}",
+        if bytes == 1 { "" } else { "s" },
+        if bytes == 1 { "s" } else { "" },
+        ranges.len(),
+        if ranges.len() == 1 { "" } else { "s" }
+    )?;
+
+    for hunk in hunks(&ranges, expected.len()) {
+        let names: Vec<_> = hunk.ranges.iter().map(range_name).collect();
+        writeln!(out, "\nat {}", names.join(", "))?;
+        writeln!(
+            out,
+            "        {:<width$}  actual",
+            "expected",
+            width = ROW_SIZE * 3
+        )?;
+        for row in hunk.rows {
+            let start = row * ROW_SIZE;
+            let end = (start + ROW_SIZE).min(expected.len());
+            let differs = expected[start..end] != actual[start..end];
+            writeln!(
+                out,
+                "{} {start:04X}  {:<width$}  {}",
+                if differs { '*' } else { ' ' },
+                hex_row(&expected[start..end]),
+                hex_row(&actual[start..end]),
+                width = ROW_SIZE * 3
+            )?;
+        }
+        for addr in hunk.ranges.iter().flat_map(Range::clone) {
+            let writer = match last_writer(addr as u16) {
+                Some(pc) => match actual
+                    .get(pc as usize..)
+                    .and_then(|memory| disassemble_one(memory, pc))
+                {
+                    Some(line) => format!("last written by `{}` at 0x{pc:04X}", line.text),
+                    None => format!("last written by the instruction at 0x{pc:04X}"),
+                },
+                None => "never written".to_owned(),
+            };
+            writeln!(
+                out,
+                "    0x{addr:04X}: expected 0x{:02X}, was 0x{:02X}, {writer}",
+                expected[addr], actual[addr]
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Differing ranges close enough together to share hexdump rows
+struct Hunk {
+    ranges: Vec<Range<usize>>,
+    rows: Range<usize>,
+}
+
+/// Groups `ranges` so that each hunk shows one row of context either side of its differences,
+/// without showing any row twice
+fn hunks(ranges: &[Range<usize>], len: usize) -> Vec<Hunk> {
+    let last_row = len.saturating_sub(1) / ROW_SIZE;
+    let mut hunks: Vec<Hunk> = vec![];
+    for range in ranges {
+        let rows = (range.start / ROW_SIZE).saturating_sub(1)
+            ..((range.end - 1) / ROW_SIZE + 1).min(last_row) + 1;
+        match hunks.last_mut() {
+            Some(hunk) if rows.start <= hunk.rows.end => {
+                hunk.ranges.push(range.clone());
+                hunk.rows.end = rows.end;
+            }
+            _ => hunks.push(Hunk {
+                ranges: vec![range.clone()],
+                rows,
+            }),
+        }
+    }
+    hunks
+}
+
+fn range_name(range: &Range<usize>) -> String {
+    match range.len() {
+        1 => format!("0x{:04X}", range.start),
+        len => format!(
+            "0x{:04X}..=0x{:04X} ({len} bytes)",
+            range.start,
+            range.end - 1
+        ),
+    }
+}
+
+fn hex_row(bytes: &[u8]) -> String {
+    let bytes: Vec<_> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn groups_differences() {
+        let expected = [0u8; 0x60];
+        let mut actual = expected;
+        actual[0x02..0x05].fill(1);
+        actual[0x07] = 1;
+        actual[0x5F] = 1;
+        assert_eq!(
+            diff_memory(&expected, &actual),
+            [0x02..0x05, 0x07..0x08, 0x5F..0x60]
+        );
+        // Bytes past the end of the expected image are not compared
+        let ranges = diff_memory(&expected[..0x07], &actual);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], 0x02..0x05);
+
+        let hunks = hunks(&diff_memory(&expected, &actual), expected.len());
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(hunks[0].ranges.len(), 2);
+        assert_eq!(hunks[0].rows, 0..2);
+        assert_eq!(hunks[1].rows, 4..6);
+    }
+
+    #[test]
+    fn reports_last_writer() {
+        let program = assemble(
+            "
+            LOAD ACC, #0x0A
+            STORE ACC, [0x0022]
+            INC [0x0023]
+            HALT
+            ",
+        )
+        .unwrap();
+        let mut computer = Computer::with_cache(program, CacheConfig::default());
+        computer.record_writers();
+        computer.run().unwrap();
+        assert_eq!(computer.last_writer(0x0022), Some(0x0002));
+        assert_eq!(computer.last_writer(0x0023), Some(0x0005));
+        assert_eq!(computer.last_writer(0x0000), None);
+
+        let mut expected = program;
+        expected[0x0022] = 0x0A;
+        expected[0x0024] = 0x01;
+        let mut out = vec![];
+        write_memory_diff(
+            &expected[..0x40],
+            computer.memory(),
+            |addr| computer.last_writer(addr),
+            &mut out,
+        )
+        .unwrap();
+        let row = |marker, addr, bytes: &str| format!("{marker} {addr}  {bytes:<48}  ");
+        let zeros = ["00"; 16].join(" ");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            [
+                "2 bytes differ in 1 range".to_owned(),
+                String::new(),
+                "at 0x0023..=0x0024 (2 bytes)".to_owned(),
+                format!("        {:<48}  actual", "expected"),
+                row(' ', "0010", &zeros) + &zeros,
+                row(
+                    '*',
+                    "0020",
+                    "00 00 0A 00 01 00 00 00 00 00 00 00 00 00 00 00"
+                ) + "00 00 0A 01 00 00 00 00 00 00 00 00 00 00 00 00",
+                row(' ', "0030", &zeros) + &zeros,
+                "    0x0023: expected 0x00, was 0x01, last written by `INC [0x0023]` at 0x0005"
+                    .to_owned(),
+                "    0x0024: expected 0x01, was 0x00, never written".to_owned(),
+                String::new(),
+            ]
+            .join("\n")
+        );
+    }
+}
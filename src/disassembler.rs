@@ -0,0 +1,243 @@
+//! Turns memory images back into assembly listings, using the same syntax as the assembler
+
+use crate::{
+    operand_widths, try_parse, BranchKind, DstTarget, Instruction, MathFunction, MemoryMethod,
+    Register, SrcTarget,
+};
+use std::fmt;
+
+/// One decoded instruction, or a byte that could not be decoded
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Disassembled {
+    pub addr: u16,
+    /// The opcode followed by its operands
+    pub bytes: Vec<u8>,
+    /// `None` if the bytes are shown as data
+    pub instruction: Option<Instruction>,
+    pub text: String,
+}
+
+impl fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<_> = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect();
+        // Wide enough for three bytes, only math between two addresses needs more
+        write!(
+            f,
+            "{:04X}  {:<8}  {}",
+            self.addr,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decodes everything in `memory`, which starts at address `origin`. Opcodes that do not
+/// decode, or whose operands would run past the end of `memory`, are shown as `.byte`
+pub fn disassemble(memory: &[u8], origin: u16) -> Vec<Disassembled> {
+    let mut listing = vec![];
+    let mut offset = 0;
+    while let Some(line) = disassemble_one(&memory[offset..], origin.wrapping_add(offset as u16)) {
+        offset += line.bytes.len();
+        listing.push(line);
+    }
+    listing
+}
+
+/// Decodes the instruction at the start of `memory`, which is at address `addr`. Returns `None`
+/// if `memory` is empty
+pub fn disassemble_one(memory: &[u8], addr: u16) -> Option<Disassembled> {
+    let &opcode = memory.first()?;
+    let data = || Disassembled {
+        addr,
+        bytes: vec![opcode],
+        instruction: None,
+        text: format!(".byte 0x{opcode:02X}"),
+    };
+    let Some(instruction) = try_parse(opcode) else {
+        return Some(data());
+    };
+    let size = 1 + operand_widths(&instruction).iter().sum::<usize>();
+    let Some(bytes) = memory.get(..size) else {
+        return Some(data());
+    };
+
+    // Operands are taken in the order the cpu fetches them
+    let mut operands = bytes[1..].iter().copied();
+    let text = match &instruction {
+        Instruction::Mathmatical { func, src, dst } => {
+            let wide = *dst == DstTarget::Mar;
+            let src_text = match src {
+                SrcTarget::Indirect => "[MAR]".to_owned(),
+                SrcTarget::Acc => "ACC".to_owned(),
+                SrcTarget::Constant if wide => format!("#{}", word(&mut operands)),
+                SrcTarget::Constant => format!("#{}", byte(&mut operands)),
+                SrcTarget::Memory => format!("[{}]", word(&mut operands)),
+            };
+            let dst_text = match dst {
+                DstTarget::Indirect => "[MAR]".to_owned(),
+                DstTarget::Acc => "ACC".to_owned(),
+                DstTarget::Mar => "MAR".to_owned(),
+                DstTarget::Memory => format!("[{}]", word(&mut operands)),
+            };
+            let unary = matches!(
+                func,
+                MathFunction::Inc | MathFunction::Dec | MathFunction::Not
+            );
+            // Unary ops ignore their source, and the assembler defaults it to ACC
+            match unary && *src == SrcTarget::Acc {
+                true => format!("{} {dst_text}", math_mnemonic(*func)),
+                false => format!("{} {dst_text}, {src_text}", math_mnemonic(*func)),
+            }
+        }
+        Instruction::Load { dst, src } => {
+            let operand = match (src, dst) {
+                (MemoryMethod::Address, _) => format!("[{}]", word(&mut operands)),
+                (MemoryMethod::Constant, Register::Acc) => format!("#{}", byte(&mut operands)),
+                (MemoryMethod::Constant, Register::Mar) => format!("#{}", word(&mut operands)),
+                (MemoryMethod::Indirect, _) => "[MAR]".to_owned(),
+            };
+            format!("LOAD {}, {operand}", register_name(*dst))
+        }
+        Instruction::Store { src, dst } => {
+            let operand = match dst {
+                MemoryMethod::Address => format!("[{}]", word(&mut operands)),
+                MemoryMethod::Constant => format!("#{}", word(&mut operands)),
+                MemoryMethod::Indirect => "[MAR]".to_owned(),
+            };
+            format!("STORE {}, {operand}", register_name(*src))
+        }
+        Instruction::Branch(kind) => {
+            format!("{} {}", branch_mnemonic(*kind), word(&mut operands))
+        }
+        Instruction::Nop => "NOP".to_owned(),
+        Instruction::Hault => "HALT".to_owned(),
+    };
+    Some(Disassembled {
+        addr,
+        bytes: bytes.to_vec(),
+        instruction: Some(instruction),
+        text,
+    })
+}
+
+/// The most bytes an instruction takes: an opcode followed by a 16 bit source address and a 16
+/// bit destination address
+pub const MAX_INSTRUCTION_SIZE: usize = 5;
+
+fn byte(operands: &mut impl Iterator<Item = u8>) -> String {
+    format!("0x{:02X}", operands.next().unwrap())
+}
+
+fn word(operands: &mut impl Iterator<Item = u8>) -> String {
+    let word = u16::from_be_bytes([operands.next().unwrap(), operands.next().unwrap()]);
+    format!("0x{word:04X}")
+}
+
+fn math_mnemonic(func: MathFunction) -> &'static str {
+    match func {
+        MathFunction::And => "AND",
+        MathFunction::Or => "OR",
+        MathFunction::Xor => "XOR",
+        MathFunction::Add => "ADD",
+        MathFunction::Sub => "SUB",
+        MathFunction::Inc => "INC",
+        MathFunction::Dec => "DEC",
+        MathFunction::Not => "NOT",
+    }
+}
+
+fn branch_mnemonic(kind: BranchKind) -> &'static str {
+    match kind {
+        BranchKind::Bra => "BRA",
+        BranchKind::Brz => "BRZ",
+        BranchKind::Bne => "BNE",
+        BranchKind::Blt => "BLT",
+        BranchKind::Ble => "BLE",
+        BranchKind::Bgt => "BGT",
+        BranchKind::Bge => "BGE",
+    }
+}
+
+fn register_name(register: Register) -> &'static str {
+    match register {
+        Register::Acc => "ACC",
+        Register::Mar => "MAR",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn lists_mem_in() {
+        let memory = include!("../mem_in.txt");
+        let listing: Vec<_> = disassemble(&memory[..0x1A], 0)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "0000  0C 10 00  LOAD MAR, [0x1000]",
+                "0003  04 20 00  STORE MAR, [0x2000]",
+                "0006  09 1A     LOAD ACC, #0x1A",
+                "0008  00 20 02  STORE ACC, [0x2002]",
+                "000B  0D 20 06  LOAD MAR, #0x2006",
+                "000E  04 20 04  STORE MAR, [0x2004]",
+                "0011  08 10 00  LOAD ACC, [0x1000]",
+                "0014  D5        INC ACC",
+                "0015  02        STORE ACC, [MAR]",
+                "0016  09 F3     LOAD ACC, #0xF3",
+                "0018  00        .byte 0x00",
+                "0019  20        .byte 0x20",
+            ]
+        );
+    }
+
+    #[test]
+    fn shows_illegal_opcodes_as_data() {
+        let listing = disassemble(&[0x1A, 0x03, 0x19], 0xFF00);
+        let text: Vec<_> = listing.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, [".byte 0x1A", ".byte 0x03", "HALT"]);
+        assert_eq!(listing[2].addr, 0xFF02);
+        assert_eq!(listing[0].instruction, None);
+    }
+
+    #[test]
+    fn handles_empty_memory() {
+        assert_eq!(disassemble_one(&[], 0x1000), None);
+        assert!(disassemble(&[], 0x1000).is_empty());
+    }
+
+    #[test]
+    fn reassembles() {
+        let source = "
+            ADD MAR, #0x1234
+            SUB [0x2000], [0x3000]
+            DEC [MAR], #0x05
+            BGE 0x0010
+            LOAD MAR, [MAR]
+            STORE ACC, #0x2000
+            NOP
+            HALT
+        ";
+        let memory = assemble(source).unwrap();
+        let listing = disassemble(&memory[..20], 0);
+        let reassembled: String = listing
+            .iter()
+            .map(|line| format!("{}\n", line.text))
+            .collect();
+        assert_eq!(assemble(&reassembled).unwrap()[..20], memory[..20]);
+        assert_eq!(listing[2].text, "DEC [MAR], #0x05");
+        assert_eq!(
+            listing[1].to_string(),
+            "0003  CF 30 00 20 00  SUB [0x2000], [0x3000]"
+        );
+    }
+}
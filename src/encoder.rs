@@ -0,0 +1,230 @@
+//! Turns instructions back into the bytes [`crate::try_parse`] decodes
+
+use crate::{DstTarget, Instruction, MemoryMethod, Register, SrcTarget};
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EncodeError {
+    /// The instruction takes a different number of operands
+    OperandCount { expected: usize, found: usize },
+    /// An operand fetched as a single byte was given a value above 0xFF
+    OperandTooWide { value: u16 },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::OperandCount { expected, found } => {
+                write!(f, "expected {expected} operands, found {found}")
+            }
+            EncodeError::OperandTooWide { value } => {
+                write!(f, "operand 0x{value:X} does not fit in 8 bits")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// The opcode byte for `instruction`
+pub fn encode_opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::Mathmatical { func, src, dst } => {
+            0b1000_0000 | (*func as u8) << 4 | (*dst as u8) << 2 | *src as u8
+        }
+        Instruction::Load { dst, src } => 0b1000 | (*dst as u8) << 2 | *src as u8,
+        Instruction::Store { src, dst } => (*src as u8) << 2 | *dst as u8,
+        Instruction::Branch(kind) => 0b0001_0000 | *kind as u8,
+        Instruction::Nop => 0b0001_1000,
+        Instruction::Hault => 0b0001_1001,
+    }
+}
+
+/// The size in bytes of each operand fetched after the opcode, in the order the cpu fetches
+/// them. Math sources are fetched before destination addresses, and operands are only 16 bits
+/// wide when they are addresses or when MAR is the destination
+pub fn operand_widths(instruction: &Instruction) -> Vec<usize> {
+    match instruction {
+        Instruction::Mathmatical { src, dst, .. } => {
+            let src = match src {
+                SrcTarget::Constant if *dst == DstTarget::Mar => Some(2),
+                SrcTarget::Constant => Some(1),
+                SrcTarget::Memory => Some(2),
+                SrcTarget::Indirect | SrcTarget::Acc => None,
+            };
+            let dst = match dst {
+                DstTarget::Memory => Some(2),
+                DstTarget::Indirect | DstTarget::Acc | DstTarget::Mar => None,
+            };
+            src.into_iter().chain(dst).collect()
+        }
+        Instruction::Load { dst, src } => match (src, dst) {
+            (MemoryMethod::Constant, Register::Acc) => vec![1],
+            (MemoryMethod::Constant, Register::Mar) | (MemoryMethod::Address, _) => vec![2],
+            (MemoryMethod::Indirect, _) => vec![],
+        },
+        Instruction::Store { dst, .. } => match dst {
+            MemoryMethod::Address | MemoryMethod::Constant => vec![2],
+            MemoryMethod::Indirect => vec![],
+        },
+        Instruction::Branch(_) => vec![2],
+        Instruction::Nop | Instruction::Hault => vec![],
+    }
+}
+
+/// The opcode for `instruction` followed by `operands`, which are given in the order the cpu
+/// fetches them. 16 bit operands are written big endian
+pub fn encode(instruction: &Instruction, operands: &[u16]) -> Result<Vec<u8>, EncodeError> {
+    let widths = operand_widths(instruction);
+    if widths.len() != operands.len() {
+        return Err(EncodeError::OperandCount {
+            expected: widths.len(),
+            found: operands.len(),
+        });
+    }
+    let mut bytes = vec![encode_opcode(instruction)];
+    for (&width, &value) in widths.iter().zip(operands) {
+        match width {
+            1 => {
+                bytes.push(u8::try_from(value).map_err(|_| EncodeError::OperandTooWide { value })?)
+            }
+            _ => bytes.extend(value.to_be_bytes()),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    /// Every instruction that can be represented, whether or not it decodes
+    fn every_instruction() -> Vec<Instruction> {
+        use MathFunction::*;
+        let funcs = [And, Or, Xor, Add, Sub, Inc, Dec, Not];
+        let srcs = [
+            SrcTarget::Indirect,
+            SrcTarget::Acc,
+            SrcTarget::Constant,
+            SrcTarget::Memory,
+        ];
+        let dsts = [
+            DstTarget::Indirect,
+            DstTarget::Acc,
+            DstTarget::Mar,
+            DstTarget::Memory,
+        ];
+        let registers = [Register::Acc, Register::Mar];
+        let methods = [
+            MemoryMethod::Address,
+            MemoryMethod::Constant,
+            MemoryMethod::Indirect,
+        ];
+        let branches = [
+            BranchKind::Bra,
+            BranchKind::Brz,
+            BranchKind::Bne,
+            BranchKind::Blt,
+            BranchKind::Ble,
+            BranchKind::Bgt,
+            BranchKind::Bge,
+        ];
+
+        let mut instructions = vec![Instruction::Nop, Instruction::Hault];
+        for func in funcs {
+            for src in srcs {
+                for dst in dsts {
+                    instructions.push(Instruction::Mathmatical { func, src, dst });
+                }
+            }
+        }
+        for reg in registers {
+            for method in methods {
+                instructions.push(Instruction::Load {
+                    dst: reg,
+                    src: method,
+                });
+                instructions.push(Instruction::Store {
+                    src: reg,
+                    dst: method,
+                });
+            }
+        }
+        instructions.extend(branches.map(Instruction::Branch));
+        instructions
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let mut legal = 0;
+        for opcode in 0..=u8::MAX {
+            if let Some(instruction) = try_parse(opcode) {
+                assert_eq!(encode_opcode(&instruction), opcode, "{instruction:?}");
+                legal += 1;
+            }
+        }
+        assert_eq!(legal, every_instruction().len());
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for instruction in every_instruction() {
+            let opcode = encode_opcode(&instruction);
+            assert_eq!(try_parse(opcode), Some(instruction), "0x{opcode:02X}");
+        }
+    }
+
+    #[test]
+    fn encodes_operands_in_fetch_order() {
+        // SUB [0x2000], [0x3000]
+        let sub = Instruction::Mathmatical {
+            func: MathFunction::Sub,
+            src: SrcTarget::Memory,
+            dst: DstTarget::Memory,
+        };
+        assert_eq!(
+            encode(&sub, &[0x3000, 0x2000]),
+            Ok(vec![0xCF, 0x30, 0x00, 0x20, 0x00])
+        );
+
+        let load = |dst| Instruction::Load {
+            dst,
+            src: MemoryMethod::Constant,
+        };
+        assert_eq!(encode(&load(Register::Acc), &[0x12]), Ok(vec![0x09, 0x12]));
+        assert_eq!(
+            encode(&load(Register::Mar), &[0x12]),
+            Ok(vec![0x0D, 0x00, 0x12])
+        );
+        assert_eq!(
+            encode(&load(Register::Acc), &[0x100]),
+            Err(EncodeError::OperandTooWide { value: 0x100 })
+        );
+        assert_eq!(
+            encode(&Instruction::Hault, &[1]),
+            Err(EncodeError::OperandCount {
+                expected: 0,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn disassembly_matches_encoding() {
+        for instruction in every_instruction() {
+            let operands: Vec<u16> = operand_widths(&instruction)
+                .iter()
+                .map(|&width| if width == 1 { 0xAB } else { 0xABCD })
+                .collect();
+            let bytes = encode(&instruction, &operands).unwrap();
+            let disassembled = disassemble_one(&bytes, 0).unwrap();
+            assert_eq!(disassembled.instruction, Some(instruction));
+            assert_eq!(disassembled.bytes, bytes);
+            assert_eq!(
+                assemble(&disassembled.text).unwrap()[..bytes.len()],
+                bytes[..]
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Faults that stop the cpu
+
+use std::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EmulatorError {
+    /// The opcode at `pc` does not decode to any instruction
+    IllegalOpcode { opcode: u8, pc: u16 },
+    /// The load or store at `pc` uses the reserved addressing mode 0b11
+    UnsupportedAddressingMode { opcode: u8, pc: u16 },
+    /// The instruction at `pc` accessed `size` bytes starting at `addr`, running past the end of
+    /// memory
+    MemoryOutOfRange { addr: u16, size: u8, pc: u16 },
+    /// The instruction at `pc` ended at the last byte of memory or ran past it, so fetching
+    /// anything more through pc would read past the end
+    PcOverflow { pc: u16 },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::IllegalOpcode { opcode, pc } => {
+                write!(f, "illegal opcode 0b{opcode:08b} at 0x{pc:04X}")
+            }
+            EmulatorError::UnsupportedAddressingMode { opcode, pc } => write!(
+                f,
+                "unsupported addressing mode in opcode 0b{opcode:08b} at 0x{pc:04X}"
+            ),
+            EmulatorError::MemoryOutOfRange { addr, size, pc } => write!(
+                f,
+                "{}-bit access to 0x{addr:04X} by the instruction at 0x{pc:04X} runs past the end of memory",
+                size * 8
+            ),
+            EmulatorError::PcOverflow { pc } => {
+                write!(f, "execution ran past the end of memory after 0x{pc:04X}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn run(program: &[u8]) -> Result<(), EmulatorError> {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..program.len()].copy_from_slice(program);
+        Computer::new(memory).run().map(|_| ())
+    }
+
+    #[test]
+    fn reports_faults() {
+        // NOP, then an opcode between HALT and the memory instructions
+        assert_eq!(
+            run(&[0x18, 0x1A]),
+            Err(EmulatorError::IllegalOpcode {
+                opcode: 0x1A,
+                pc: 0x0001
+            })
+        );
+        // STORE ACC with addressing mode 0b11
+        assert_eq!(
+            run(&[0x03]),
+            Err(EmulatorError::UnsupportedAddressingMode {
+                opcode: 0x03,
+                pc: 0x0000
+            })
+        );
+        // LOAD MAR [0xFFFF]
+        assert_eq!(
+            run(&[0x0C, 0xFF, 0xFF]),
+            Err(EmulatorError::MemoryOutOfRange {
+                addr: 0xFFFF,
+                size: 2,
+                pc: 0x0000
+            })
+        );
+    }
+
+    #[test]
+    fn stops_at_end_of_memory() {
+        // BRA 0xFFFD, then NOPs until the end of memory
+        let mut memory = [0x18; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0xFF, 0xFD]);
+        assert_eq!(
+            Computer::new(memory).run().map(|_| ()),
+            Err(EmulatorError::PcOverflow { pc: 0xFFFF })
+        );
+    }
+
+    #[test]
+    fn runs_the_last_byte_of_memory() {
+        // BRA 0xFFFF, then HALT
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0xFF, 0xFF]);
+        memory[0xFFFF] = 0x19;
+        assert_eq!(Computer::new(memory).run(), Ok(StopReason::Halted));
+
+        // BRA 0xFFFE, then LOAD ACC #0x19, whose operand is the last byte
+        memory[..3].copy_from_slice(&[0x10, 0xFF, 0xFE]);
+        memory[0xFFFE] = 0x09;
+        assert_eq!(
+            Computer::new(memory).run(),
+            Err(EmulatorError::PcOverflow { pc: 0xFFFE })
+        );
+    }
+}
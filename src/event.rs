@@ -0,0 +1,356 @@
+//! Events reported by the cpu as it executes, and observers that receive them
+
+use crate::{disassemble_one, AccessSource, Instruction, MAX_INSTRUCTION_SIZE};
+use std::fmt;
+use std::io::{self, Write};
+
+/// The cpu's registers, as named in events
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CpuRegister {
+    Pc,
+    Ir,
+    Acc,
+    Mar,
+}
+
+impl fmt::Display for CpuRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuRegister::Pc => write!(f, "PC"),
+            CpuRegister::Ir => write!(f, "IR"),
+            CpuRegister::Acc => write!(f, "ACC"),
+            CpuRegister::Mar => write!(f, "MAR"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// An opcode was loaded into IR from `pc`
+    Fetched { pc: u16, opcode: u8 },
+    /// The opcode at `pc` was decoded and is about to execute. `bytes` holds the opcode followed
+    /// by as many operand bytes as the instruction uses, padded with zeros
+    Decoded {
+        pc: u16,
+        instruction: Instruction,
+        bytes: [u8; MAX_INSTRUCTION_SIZE],
+    },
+    /// The opcode at `pc` does not decode to any instruction
+    IllegalInstruction { pc: u16, opcode: u8 },
+    /// A register was given a new value. PC advancing past the bytes it fetches is not reported,
+    /// only jumps are
+    RegisterChanged {
+        register: CpuRegister,
+        old: u16,
+        new: u16,
+    },
+    /// `size` bytes were read starting at `addr`, and make up the big endian `value`
+    MemoryRead {
+        addr: u16,
+        size: u8,
+        value: u16,
+        source: AccessSource,
+    },
+    /// `size` bytes were written starting at `addr`, making up the big endian `value`
+    MemoryWrite { addr: u16, size: u8, value: u16 },
+    /// The hault instruction at `pc` was executed
+    Halted { pc: u16 },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Fetched { pc, opcode } => write!(f, "fetched 0x{opcode:02X} from 0x{pc:04X}"),
+            Event::Decoded { pc, bytes, .. } => {
+                write!(f, "executing {} at 0x{pc:04X}", disassembly(*pc, bytes))
+            }
+            Event::IllegalInstruction { pc, opcode } => {
+                write!(f, "illegal instruction 0b{opcode:08b} at 0x{pc:04X}")
+            }
+            Event::RegisterChanged { register, old, new } => {
+                write!(f, "{register}: 0x{old:X} -> 0x{new:X}")
+            }
+            Event::MemoryRead {
+                addr,
+                size,
+                value,
+                source,
+            } => write!(
+                f,
+                "read {} bits: 0x{value:X} from [0x{addr:04X}] ({source:?})",
+                size * 8
+            ),
+            Event::MemoryWrite { addr, size, value } => {
+                write!(f, "wrote {} bits: 0x{value:X} to [0x{addr:04X}]", size * 8)
+            }
+            Event::Halted { pc } => write!(f, "halted at 0x{pc:04X}"),
+        }
+    }
+}
+
+impl Event {
+    /// Formats the event as a single line json object
+    pub fn to_json(&self) -> String {
+        match self {
+            Event::Fetched { pc, opcode } => {
+                format!(r#"{{"event": "fetched", "pc": {pc}, "opcode": {opcode}}}"#)
+            }
+            Event::Decoded { pc, bytes, .. } => format!(
+                r#"{{"event": "decoded", "pc": {pc}, "instruction": "{}"}}"#,
+                disassembly(*pc, bytes)
+            ),
+            Event::IllegalInstruction { pc, opcode } => {
+                format!(r#"{{"event": "illegal_instruction", "pc": {pc}, "opcode": {opcode}}}"#)
+            }
+            Event::RegisterChanged { register, old, new } => format!(
+                r#"{{"event": "register_changed", "register": "{register}", "old": {old}, "new": {new}}}"#
+            ),
+            Event::MemoryRead {
+                addr,
+                size,
+                value,
+                source,
+            } => format!(
+                r#"{{"event": "memory_read", "addr": {addr}, "size": {size}, "value": {value}, "source": "{source:?}"}}"#
+            ),
+            Event::MemoryWrite { addr, size, value } => format!(
+                r#"{{"event": "memory_write", "addr": {addr}, "size": {size}, "value": {value}}}"#
+            ),
+            Event::Halted { pc } => format!(r#"{{"event": "halted", "pc": {pc}}}"#),
+        }
+    }
+}
+
+/// The instruction in `bytes` as the disassembler writes it
+fn disassembly(pc: u16, bytes: &[u8; MAX_INSTRUCTION_SIZE]) -> String {
+    // Never empty, so there is always a line
+    disassemble_one(bytes, pc).unwrap().text
+}
+
+/// Receives every event from a [`crate::Computer`]
+pub trait Observer {
+    fn event(&mut self, event: &Event);
+
+    /// The first error hit while handling events. Observers that fail stop handling events
+    /// instead of panicking, since that would take the cpu down with them
+    fn error(&self) -> Option<&io::Error> {
+        None
+    }
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Ignores every event
+pub struct Silent;
+
+impl Observer for Silent {
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Writes each event as a line of text, until a write fails
+pub struct HumanReadable<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> HumanReadable<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, error: None }
+    }
+}
+
+impl<W: Write> Observer for HumanReadable<W> {
+    fn event(&mut self, event: &Event) {
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{event}").err();
+        }
+    }
+
+    fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+/// Writes each event as a line of json, until a write fails
+pub struct MachineReadable<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> MachineReadable<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, error: None }
+    }
+}
+
+impl<W: Write> Observer for MachineReadable<W> {
+    fn event(&mut self, event: &Event) {
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{}", event.to_json()).err();
+        }
+    }
+
+    fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn reports_execution() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LOAD ACC #5, STORE ACC [0x2000], HALT
+        memory[..6].copy_from_slice(&[0x09, 0x05, 0x00, 0x20, 0x00, 0x19]);
+        let mut computer = Computer::new(memory);
+        let events = Rc::new(RefCell::new(vec![]));
+        let recorded = Rc::clone(&events);
+        computer.set_observer(move |event: &Event| recorded.borrow_mut().push(event.clone()));
+        computer.run().unwrap();
+
+        let load = Instruction::Load {
+            dst: Register::Acc,
+            src: MemoryMethod::Constant,
+        };
+        let store = Instruction::Store {
+            src: Register::Acc,
+            dst: MemoryMethod::Address,
+        };
+        let read = |addr, size, value| Event::MemoryRead {
+            addr,
+            size,
+            value,
+            source: AccessSource::Instruction,
+        };
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                read(0x0000, 1, 0x09),
+                Event::Fetched {
+                    pc: 0x0000,
+                    opcode: 0x09
+                },
+                Event::Decoded {
+                    pc: 0x0000,
+                    instruction: load,
+                    bytes: [0x09, 0x05, 0x00, 0x00, 0x00]
+                },
+                read(0x0001, 1, 0x05),
+                Event::RegisterChanged {
+                    register: CpuRegister::Acc,
+                    old: 0,
+                    new: 5
+                },
+                read(0x0002, 1, 0x00),
+                Event::Fetched {
+                    pc: 0x0002,
+                    opcode: 0x00
+                },
+                Event::Decoded {
+                    pc: 0x0002,
+                    instruction: store,
+                    bytes: [0x00, 0x20, 0x00, 0x00, 0x00]
+                },
+                read(0x0003, 2, 0x2000),
+                Event::MemoryWrite {
+                    addr: 0x2000,
+                    size: 1,
+                    value: 5
+                },
+                read(0x0005, 1, 0x19),
+                Event::Fetched {
+                    pc: 0x0005,
+                    opcode: 0x19
+                },
+                Event::Decoded {
+                    pc: 0x0005,
+                    instruction: Instruction::Hault,
+                    bytes: [0x19, 0x00, 0x00, 0x00, 0x00]
+                },
+                Event::Halted { pc: 0x0005 },
+            ]
+        );
+    }
+
+    #[test]
+    fn describes_the_longest_instructions() {
+        let mut memory = [0; MEMORY_SIZE];
+        // SUB [0x2000], [0x3000], HALT
+        memory[..6].copy_from_slice(&[0xCF, 0x30, 0x00, 0x20, 0x00, 0x19]);
+        let mut computer = Computer::new(memory);
+        let executed = Rc::new(RefCell::new(vec![]));
+        let recorded = Rc::clone(&executed);
+        computer.set_observer(move |event: &Event| {
+            if let Event::Decoded { .. } = event {
+                recorded.borrow_mut().push(event.to_string());
+            }
+        });
+        computer.run().unwrap();
+        assert_eq!(
+            *executed.borrow(),
+            [
+                "executing SUB [0x2000], [0x3000] at 0x0000",
+                "executing HALT at 0x0005"
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_events() {
+        let event = Event::MemoryWrite {
+            addr: 0x2000,
+            size: 2,
+            value: 0x1234,
+        };
+        assert_eq!(event.to_string(), "wrote 16 bits: 0x1234 to [0x2000]");
+        assert_eq!(
+            event.to_json(),
+            r#"{"event": "memory_write", "addr": 8192, "size": 2, "value": 4660}"#
+        );
+
+        let event = Event::Decoded {
+            pc: 0x0100,
+            instruction: Instruction::Load {
+                dst: Register::Acc,
+                src: MemoryMethod::Constant,
+            },
+            bytes: [0x09, 0x1A, 0x00, 0x00, 0x00],
+        };
+        assert_eq!(event.to_string(), "executing LOAD ACC, #0x1A at 0x0100");
+        assert_eq!(
+            event.to_json(),
+            r#"{"event": "decoded", "pc": 256, "instruction": "LOAD ACC, #0x1A"}"#
+        );
+
+        let mut out = vec![];
+        MachineReadable::new(&mut out).event(&Event::Halted { pc: 3 });
+        HumanReadable::new(&mut out).event(&Event::Halted { pc: 3 });
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"event\": \"halted\", \"pc\": 3}\nhalted at 0x0003\n"
+        );
+    }
+
+    #[test]
+    fn stops_writing_after_an_error() {
+        // Only room for the first event
+        let mut out = [0; 20];
+        let mut observer = HumanReadable::new(&mut out[..]);
+        observer.event(&Event::Halted { pc: 3 });
+        assert!(observer.error().is_none());
+        observer.event(&Event::Halted { pc: 0x1234 });
+        assert_eq!(observer.error().unwrap().kind(), io::ErrorKind::WriteZero);
+        observer.event(&Event::Halted { pc: 5 });
+        assert_eq!(observer.error().unwrap().kind(), io::ErrorKind::WriteZero);
+        assert!(out.starts_with(b"halted at 0x0003\nhal"));
+    }
+}
@@ -0,0 +1,576 @@
+//! A GDB remote serial protocol server, so programs can be debugged with gdb or any other
+//! front end that speaks the protocol.
+//!
+//! Registers are numbered PC (0), IR (1), ACC (2) and MAR (3), and are sent big endian like the
+//! operands the cpu fetches, so `g` replies with `PPPPIIAAMMMM`. Software and hardware
+//! breakpoints behave the same, since breakpoints are checked by the emulator rather than
+//! patched into memory. Write, read and access watchpoints map onto [`crate::Watchpoint`]s.
+//! Executing a halt instruction ends the session like a process exiting. Reverse stepping and
+//! continuing run back through the last million or so instructions.
+//! Requests to interrupt a running program are not supported, so continuing stops with SIGTRAP
+//! after ten million instructions instead of running forever
+
+use crate::debugger::CONTINUE_LIMIT;
+use crate::history::DEBUG_HISTORY_LIMIT;
+use crate::{Computer, EmulatorError, StopReason, WatchKind, Watchpoint, MEMORY_SIZE};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// Largest packet we accept, advertised to the client in hex
+const PACKET_SIZE: usize = 0x1000;
+
+/// Signal numbers reported in stop replies
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Drives a [`Computer`] on behalf of a gdb client
+pub struct GdbStub {
+    computer: Computer,
+    breakpoints: BTreeSet<u16>,
+    /// Cleared once the client asks to stop acknowledging packets
+    acks: bool,
+    /// The most instructions a single continue runs before stopping
+    continue_limit: u64,
+}
+
+impl GdbStub {
+    /// Serves `computer`, recording its history so the client can reverse execution
+    pub fn new(mut computer: Computer) -> Self {
+        computer.record_history(DEBUG_HISTORY_LIMIT);
+        Self {
+            computer,
+            breakpoints: BTreeSet::new(),
+            acks: true,
+            continue_limit: CONTINUE_LIMIT,
+        }
+    }
+
+    pub fn computer(&self) -> &Computer {
+        &self.computer
+    }
+
+    /// Answers packets from `input` until the client detaches or kills the program, or the
+    /// connection closes
+    pub fn serve(&mut self, input: impl Read, mut output: impl Write) -> io::Result<()> {
+        let mut input = BufReader::new(input);
+        while let Some(packet) = read_packet(&mut input)? {
+            let Some(packet) = packet else {
+                // Without acknowledgements the client will not resend it, so there is no point
+                // asking
+                if self.acks {
+                    output.write_all(b"-")?;
+                    output.flush()?;
+                }
+                continue;
+            };
+            if self.acks {
+                output.write_all(b"+")?;
+            }
+            let (reply, done) = match packet.first() {
+                Some(b'k') => (None, true),
+                Some(b'D') => (Some("OK".to_owned()), true),
+                _ => (Some(self.answer(&packet)), false),
+            };
+            if let Some(reply) = reply {
+                write_packet(&mut output, &reply)?;
+            }
+            output.flush()?;
+            if done {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to a single packet. Anything unsupported gets an empty reply, as the protocol
+    /// asks
+    fn answer(&mut self, packet: &[u8]) -> String {
+        let Ok(packet) = std::str::from_utf8(packet) else {
+            return String::new();
+        };
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => format!("S{SIGTRAP:02X}"),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.resume(args, |computer| computer.run_for(1)),
+            "c" => {
+                let breakpoints = self.breakpoints.clone();
+                let limit = self.continue_limit;
+                self.resume(args, |computer| {
+                    let mut executed = 0;
+                    computer.run_until(|computer| {
+                        executed += 1;
+                        breakpoints.contains(&computer.pc()) || executed == limit
+                    })
+                })
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            _ if packet == "bs" => self.reverse(|computer| computer.step_back()),
+            _ if packet == "bc" => {
+                let breakpoints = self.breakpoints.clone();
+                self.reverse(|computer| {
+                    while computer.step_back() {
+                        if breakpoints.contains(&computer.pc()) {
+                            return true;
+                        }
+                    }
+                    false
+                })
+            }
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={PACKET_SIZE:X};QStartNoAckMode+;ReverseStep+;ReverseContinue+")
+            }
+            _ if packet == "QStartNoAckMode" => {
+                self.acks = false;
+                "OK".to_owned()
+            }
+            _ if packet == "qAttached" => "1".to_owned(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let state = self.computer.state();
+        format!(
+            "{:04X}{:02X}{:02X}{:04X}",
+            state.pc, state.ir, state.acc, state.mar
+        )
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = decode_hex(args).filter(|bytes| bytes.len() == 6);
+        let Some(bytes) = bytes else {
+            return error(1);
+        };
+        let mut state = self.computer.state();
+        state.pc = u16::from_be_bytes([bytes[0], bytes[1]]);
+        state.ir = bytes[2];
+        state.acc = bytes[3];
+        state.mar = u16::from_be_bytes([bytes[4], bytes[5]]);
+        self.computer.set_state(state);
+        "OK".to_owned()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let state = self.computer.state();
+        match u8::from_str_radix(args, 16) {
+            Ok(0) => format!("{:04X}", state.pc),
+            Ok(1) => format!("{:02X}", state.ir),
+            Ok(2) => format!("{:02X}", state.acc),
+            Ok(3) => format!("{:04X}", state.mar),
+            _ => error(1),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(register, value)| {
+            Some((u8::from_str_radix(register, 16).ok()?, decode_hex(value)?))
+        });
+        match parsed
+            .as_ref()
+            .map(|(register, value)| (*register, &value[..]))
+        {
+            Some((0, &[high, low])) => self.computer.set_pc(u16::from_be_bytes([high, low])),
+            Some((1, &[value])) => self.computer.set_ir(value),
+            Some((2, &[value])) => self.computer.set_acc(value),
+            Some((3, &[high, low])) => self.computer.set_mar(u16::from_be_bytes([high, low])),
+            _ => return error(1),
+        }
+        "OK".to_owned()
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args) else {
+            return error(1);
+        };
+        // An empty reply would mean the packet is unsupported
+        if len == 0 {
+            return "OK".to_owned();
+        }
+        (addr..addr + len)
+            .map(|addr| format!("{:02X}", self.computer.peek(addr as u16)))
+            .collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = parse_range(range)?;
+            let data = decode_hex(data).filter(|data| data.len() == len)?;
+            Some((addr, data))
+        });
+        let Some((addr, data)) = parsed else {
+            return error(1);
+        };
+        for (i, byte) in data.into_iter().enumerate() {
+            self.computer.poke((addr + i) as u16, byte);
+        }
+        "OK".to_owned()
+    }
+
+    /// Continues from the optional address in `args`, then describes why execution stopped
+    fn resume(
+        &mut self,
+        args: &str,
+        run: impl FnOnce(&mut Computer) -> Result<StopReason, EmulatorError>,
+    ) -> String {
+        if !args.is_empty() {
+            let Ok(addr) = u16::from_str_radix(args, 16) else {
+                return error(1);
+            };
+            self.computer.set_pc(addr);
+        }
+        match run(&mut self.computer) {
+            Ok(StopReason::Halted) => "W00".to_owned(),
+            Ok(StopReason::Watchpoint) => {
+                let hit = self.computer.watch_hit().unwrap();
+                let kind = match self.computer.watchpoints()[hit.watchpoint].kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                // Report the first watched byte the access touched
+                let watched = self.computer.watchpoints()[hit.watchpoint].range.start();
+                let addr = hit.addr.max(*watched);
+                format!("T{SIGTRAP:02X}{kind}:{addr:X};")
+            }
+            Ok(_) => format!("S{SIGTRAP:02X}"),
+            Err(EmulatorError::IllegalOpcode { .. })
+            | Err(EmulatorError::UnsupportedAddressingMode { .. }) => format!("S{SIGILL:02X}"),
+            Err(EmulatorError::MemoryOutOfRange { .. }) | Err(EmulatorError::PcOverflow { .. }) => {
+                format!("S{SIGSEGV:02X}")
+            }
+        }
+    }
+
+    /// Runs backwards, reporting the start of the history if `run` returns false because it
+    /// reached it
+    fn reverse(&mut self, run: impl FnOnce(&mut Computer) -> bool) -> String {
+        match run(&mut self.computer) {
+            true => format!("S{SIGTRAP:02X}"),
+            false => format!("T{SIGTRAP:02X}replaylog:begin;"),
+        }
+    }
+
+    /// Handles `Z` and `z` packets for software (type 0) and hardware (type 1) breakpoints, and
+    /// write (type 2), read (type 3) and access (type 4) watchpoints
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return error(1);
+        };
+        let (Ok(addr), Ok(len)) = (u16::from_str_radix(addr, 16), u16::from_str_radix(len, 16))
+        else {
+            return error(1);
+        };
+        let kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_owned();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let Some(last) = addr.checked_add(len.max(1) - 1) else {
+            return error(1);
+        };
+        let watchpoint = Watchpoint::new(addr..=last, kind);
+        if insert {
+            self.computer.add_watchpoint(watchpoint);
+        } else if let Some(index) = self
+            .computer
+            .watchpoints()
+            .iter()
+            .position(|existing| *existing == watchpoint)
+        {
+            self.computer.remove_watchpoint(index);
+        }
+        "OK".to_owned()
+    }
+}
+
+fn error(code: u8) -> String {
+    format!("E{code:02X}")
+}
+
+/// Parses `addr,len` in hex, checking the range stays inside memory
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    addr.checked_add(len).filter(|&end| end <= MEMORY_SIZE)?;
+    Some((addr, len))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Reads the next packet, skipping acknowledgements and interrupt requests. Returns `None` once
+/// the input ends, and `Some(None)` for a packet whose checksum does not match
+fn read_packet(input: &mut impl BufRead) -> io::Result<Option<Option<Vec<u8>>>> {
+    let mut byte = [0];
+    loop {
+        if input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut raw = vec![];
+    input.read_until(b'#', &mut raw)?;
+    if raw.pop() != Some(b'#') {
+        return Ok(None);
+    }
+    let mut checksum = [0; 2];
+    input.read_exact(&mut checksum)?;
+    let expected = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+    if expected != Some(checksum_of(&raw)) {
+        return Ok(Some(None));
+    }
+
+    // `}` escapes the byte after it, which is xored with 0x20
+    let mut packet = Vec::with_capacity(raw.len());
+    let mut raw = raw.into_iter();
+    while let Some(byte) = raw.next() {
+        match byte {
+            b'}' => packet.extend(raw.next().map(|byte| byte ^ 0x20)),
+            _ => packet.push(byte),
+        }
+    }
+    Ok(Some(Some(packet)))
+}
+
+fn write_packet(output: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(output, "${data}#{:02x}", checksum_of(data.as_bytes()))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::net::{TcpListener, TcpStream};
+
+    fn packet(data: &str) -> String {
+        let mut out = vec![];
+        write_packet(&mut out, data).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Sends each of `packets` to a stub running the countdown program, returning its replies
+    fn session(packets: &[&str]) -> (GdbStub, Vec<String>) {
+        // LOAD ACC #3, DEC ACC, BNE 0x0002, HALT
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..7].copy_from_slice(&[0x09, 0x03, 0xE5, 0x12, 0x00, 0x02, 0x19]);
+        let mut stub = GdbStub::new(Computer::new(memory));
+        let input: String = packets.iter().map(|data| packet(data) + "+").collect();
+        let mut output = vec![];
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|reply| {
+                let (data, checksum) = reply.split_once('#').unwrap();
+                assert_eq!(
+                    checksum[..2],
+                    format!("{:02x}", checksum_of(data.as_bytes()))
+                );
+                data.to_owned()
+            })
+            .collect();
+        (stub, replies)
+    }
+
+    #[test]
+    fn reads_and_writes_state() {
+        let (stub, replies) = session(&[
+            "qSupported:swbreak+",
+            "?",
+            "s",
+            "g",
+            "P2=07",
+            "p2",
+            "G0002E505ABCD",
+            "m0000,3",
+            "M2000,2:4142",
+            "m2000,2",
+            "m0ffff,2",
+            "m2000,0",
+            "vMustReplyEmpty",
+        ]);
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000;QStartNoAckMode+;ReverseStep+;ReverseContinue+",
+                "S05",
+                "S05",
+                "000209030000",
+                "OK",
+                "07",
+                "OK",
+                "0903E5",
+                "OK",
+                "4142",
+                "E01",
+                "OK",
+                "",
+            ]
+        );
+        assert_eq!(
+            stub.computer().state(),
+            CpuState {
+                pc: 0x0002,
+                ir: 0xE5,
+                acc: 0x05,
+                mar: 0xABCD,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_ranges() {
+        let (_, replies) = session(&[
+            "mffffffffffffffff,1",
+            "m1,ffffffffffffffff",
+            "Mffffffffffffffff,1:41",
+            "M1,ffffffffffffffff:41",
+        ]);
+        assert_eq!(replies, ["E01", "E01", "E01", "E01"]);
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let (stub, replies) = session(&["Z0,3,1", "c", "c", "p2", "z0,3,1", "c", "c", "k"]);
+        assert_eq!(replies, ["OK", "S05", "S05", "01", "OK", "W00", "W00"]);
+        assert!(stub.computer().halted());
+
+        let (stub, replies) = session(&["c", "Z0,3,1", "bc", "p2", "bs", "g", "z0,3,1", "bc"]);
+        assert_eq!(
+            replies,
+            [
+                "W00",
+                "OK",
+                "S05",
+                "00",
+                "S05",
+                "000212010000",
+                "OK",
+                "T05replaylog:begin;"
+            ]
+        );
+        assert_eq!(stub.computer().state(), CpuState::default());
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        // Store each count to 0x2000 before decrementing it
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..10].copy_from_slice(&[0x09, 0x02, 0x00, 0x20, 0x00, 0xE5, 0x12, 0x00, 0x02, 0x19]);
+        let mut stub = GdbStub::new(Computer::new(memory));
+        let input: String = ["Z2,1fff,2", "c", "c", "p2", "z2,1fff,2", "Z9,0,1", "c"]
+            .iter()
+            .map(|data| packet(data))
+            .collect();
+        let mut output = vec![];
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+        let expected: String = [
+            "OK",
+            "T05watch:2000;",
+            "T05watch:2000;",
+            "01",
+            "OK",
+            "",
+            "W00",
+        ]
+        .iter()
+        .map(|data| "+".to_owned() + &packet(data))
+        .collect();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut stub = GdbStub::new(Computer::new([0; MEMORY_SIZE]));
+        let mut output = vec![];
+        stub.serve(&b"$g#00$g#67"[..], &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "-+$000000000000#40");
+
+        let mut output = vec![];
+        let input = packet("QStartNoAckMode") + "$g#00" + &packet("g");
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "+".to_owned() + &packet("OK") + &packet("000000000000")
+        );
+    }
+
+    #[test]
+    fn stops_continuing_after_a_limit() {
+        // BRA 0x0000
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let mut stub = GdbStub::new(Computer::new(memory));
+        stub.continue_limit = 100;
+        let mut output = vec![];
+        stub.serve(packet("c").as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("+{}", packet("S05"))
+        );
+        assert_eq!(stub.computer().timing().instructions, 100);
+    }
+
+    #[test]
+    fn serves_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(packet("QStartNoAckMode").as_bytes())
+                .unwrap();
+            stream.write_all(packet("m0000,2").as_bytes()).unwrap();
+            stream.write_all(packet("D").as_bytes()).unwrap();
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).unwrap();
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut memory = [0; MEMORY_SIZE];
+        memory[0] = 0x19;
+        GdbStub::new(Computer::new(memory))
+            .serve(stream.try_clone().unwrap(), stream)
+            .unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            "+".to_owned() + &packet("OK") + &packet("1900") + &packet("OK")
+        );
+    }
+}
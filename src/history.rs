@@ -0,0 +1,165 @@
+//! An undo log of the changes each instruction makes, so the cpu can step backwards
+
+use crate::CpuState;
+use std::collections::VecDeque;
+
+/// How many instructions the interactive debuggers can step back through
+pub(crate) const DEBUG_HISTORY_LIMIT: usize = 1 << 20;
+
+/// Everything an instruction changed, recorded as the values from before it ran
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct UndoStep {
+    pub state: CpuState,
+    pub instruction_pc: u16,
+    pub pc_past_end: Option<u16>,
+    pub halted: bool,
+    pub instructions: u64,
+    pub execute_cycles: u64,
+    /// The address and old value of every byte written, in the order they were written
+    pub writes: Vec<(u16, u8)>,
+}
+
+/// The most recent instructions' undo steps, oldest first
+pub(crate) struct History {
+    steps: VecDeque<UndoStep>,
+    /// Oldest steps are forgotten once there are this many
+    limit: usize,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            steps: VecDeque::new(),
+            limit,
+        }
+    }
+
+    pub fn push(&mut self, step: UndoStep) {
+        if self.steps.len() == self.limit {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    /// Notes that the instruction being executed is about to overwrite `old` at `addr`
+    pub fn remember(&mut self, addr: u16, old: u8) {
+        if let Some(step) = self.steps.back_mut() {
+            step.writes.push((addr, old));
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<UndoStep> {
+        self.steps.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    /// Counts down from 3 storing each value to 0x2000, then stores 0xFF over 0x2001 and 0x2002
+    fn countdown() -> Computer {
+        let program = assemble(
+            "
+                LOAD ACC, #3
+            loop:
+                STORE ACC, [0x2000]
+                DEC ACC
+                BNE loop
+                LOAD MAR, #0xFFFF
+                STORE MAR, [0x2001]
+                HALT
+            ",
+        )
+        .unwrap();
+        let mut computer = Computer::with_cache(program, CacheConfig::default());
+        computer.record_history(100);
+        computer
+    }
+
+    #[test]
+    fn steps_back_to_the_start() {
+        let mut computer = countdown();
+        let start = computer.state();
+        let mut states = vec![];
+        while !computer.halted() {
+            states.push((
+                computer.state(),
+                computer.peek(0x2000),
+                computer.peek(0x2001),
+            ));
+            computer.step().unwrap();
+        }
+        assert_eq!(computer.history_len(), states.len());
+
+        while let Some(expected) = states.pop() {
+            assert!(computer.step_back());
+            let actual = (
+                computer.state(),
+                computer.peek(0x2000),
+                computer.peek(0x2001),
+            );
+            assert_eq!(actual, expected);
+            assert!(!computer.halted());
+        }
+        assert!(!computer.step_back());
+        assert_eq!(computer.state(), start);
+        assert_eq!(computer.timing().instructions, 0);
+        assert_eq!(computer.peek(0x2002), 0);
+
+        // Replaying gives the same result as the first run
+        computer.run().unwrap();
+        assert_eq!(computer.memory()[0x2000..0x2003], [1, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn runs_back_to_stores() {
+        let mut computer = countdown();
+        computer.run().unwrap();
+
+        // Find the store that last wrote 0x2000
+        assert!(computer.run_back_to(0x0002));
+        assert_eq!(computer.acc(), 1);
+        assert_eq!(computer.peek(0x2000), 2);
+        assert!(computer.run_back_to(0x0002));
+        assert_eq!(computer.acc(), 2);
+
+        assert!(computer.rewind_to(1));
+        assert_eq!(computer.timing().instructions, 1);
+        assert_eq!(computer.state().pc, 0x0002);
+        assert!(!computer.rewind_to(5));
+        assert!(!computer.run_back_to(0x0100));
+        assert_eq!(computer.state().pc, 0x0000);
+    }
+
+    #[test]
+    fn forgets_old_steps() {
+        let mut computer = countdown();
+        computer.record_history(3);
+        computer.run().unwrap();
+        assert_eq!(computer.history_len(), 3);
+        assert!(!computer.rewind_to(0));
+        assert_eq!(computer.state().pc, 0x0009);
+    }
+
+    #[test]
+    fn steps_back_from_the_end_of_memory() {
+        // BRA 0xFFFF, then a NOP in the last byte of memory
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0xFF, 0xFF]);
+        memory[0xFFFF] = 0x18;
+        let mut computer = Computer::new(memory);
+        computer.record_history(100);
+        computer.run_for(2).unwrap();
+        assert!(computer.step_back());
+        assert_eq!(computer.run_for(1), Ok(StopReason::InstructionLimit));
+        assert_eq!(
+            computer.step(),
+            Err(EmulatorError::PcOverflow { pc: 0xFFFF })
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! Reading and writing memory images as raw bytes, a bracketed hex array like `mem_in.txt`,
+//! Intel HEX or Motorola S-records
+
+use crate::MEMORY_SIZE;
+use std::io::{self, Read, Write};
+
+/// Number of data bytes in each Intel HEX or S-record record we write
+const RECORD_SIZE: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Binary,
+    /// A bracketed hex array like `mem_in.txt`
+    Text,
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    /// Picks a format from the extension of `path`: `.bin`, `.txt`, `.hex` or `.ihex`, and
+    /// `.srec`, `.s19` or `.mot`. Other extensions do not name a format
+    pub fn from_extension(path: &str) -> Option<ImageFormat> {
+        let extension = path.rsplit_once('.').map(|(_, extension)| extension);
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("bin") => Some(ImageFormat::Binary),
+            Some("txt") => Some(ImageFormat::Text),
+            Some("hex" | "ihex") => Some(ImageFormat::IntelHex),
+            Some("srec" | "s19" | "mot") => Some(ImageFormat::SRecord),
+            _ => None,
+        }
+    }
+
+    /// Like [`ImageFormat::from_extension`], but anything else is binary
+    pub fn from_path(path: &str) -> ImageFormat {
+        ImageFormat::from_extension(path).unwrap_or(ImageFormat::Binary)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads an image without knowing its format. Text starting with `[` is read as a hex array,
+/// with `:` as Intel HEX and with `S` as S-records, and anything else as raw bytes. Raw bytes
+/// can start with any of those too, so an image that does not parse as the format its first
+/// byte suggests is read as raw bytes. The image may be shorter than memory, in which case it
+/// fills memory from address zero
+pub fn read_image(mut input: impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    let format = match bytes.first() {
+        Some(b'[') => ImageFormat::Text,
+        Some(b':') => ImageFormat::IntelHex,
+        Some(b'S') => ImageFormat::SRecord,
+        _ => ImageFormat::Binary,
+    };
+    parse_image(&bytes, format).or_else(|_| parse_image(&bytes, ImageFormat::Binary))
+}
+
+/// Reads an image in `format`. Bytes that Intel HEX and S-record files leave out are zero
+pub fn read_image_as(mut input: impl Read, format: ImageFormat) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    parse_image(&bytes, format)
+}
+
+fn parse_image(bytes: &[u8], format: ImageFormat) -> io::Result<Vec<u8>> {
+    let text = || std::str::from_utf8(bytes).map_err(|_| invalid("image is not text".to_owned()));
+    let image = match format {
+        ImageFormat::Binary => bytes.to_vec(),
+        ImageFormat::Text => parse_hex_array(text()?)?,
+        ImageFormat::IntelHex => parse_intel_hex(text()?)?,
+        ImageFormat::SRecord => parse_srecords(text()?)?,
+    };
+    if image.len() > MEMORY_SIZE {
+        return Err(invalid(format!(
+            "image is {} bytes, but memory is only {MEMORY_SIZE}",
+            image.len()
+        )));
+    }
+    Ok(image)
+}
+
+/// Parses comma separated bytes between `[` and `]`. Bytes are hex with a `0x` prefix, or
+/// decimal
+fn parse_hex_array(text: &str) -> io::Result<Vec<u8>> {
+    let text = text.trim();
+    let Some(inner) = text
+        .strip_prefix('[')
+        .and_then(|text| text.strip_suffix(']'))
+    else {
+        return Err(invalid(
+            "hex array must be wrapped in `[` and `]`".to_owned(),
+        ));
+    };
+    let mut image = vec![];
+    // The opening bracket is on the first line, so line numbers still line up
+    for (number, line) in inner.lines().enumerate() {
+        for token in line
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+        {
+            let byte = match token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+            {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => token.parse(),
+            };
+            let byte =
+                byte.map_err(|_| invalid(format!("line {}: bad byte {token:?}", number + 1)))?;
+            image.push(byte);
+        }
+    }
+    Ok(image)
+}
+
+/// Writes an image as raw bytes
+pub fn write_image_binary(image: &[u8], mut out: impl Write) -> io::Result<()> {
+    out.write_all(image)
+}
+
+/// Writes an image as a hex array with 16 bytes per line, in the format `mem_out.txt` uses
+pub fn write_image_text(image: &[u8], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for line in image.chunks(16) {
+        for byte in line {
+            write!(out, "0x{byte:02x}, ")?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "]")
+}
+
+/// Writes `image` in `format`. Binary and text images hold every byte, Intel HEX and S-records
+/// only hold the [`RECORD_SIZE`] byte blocks that are not all zero
+pub fn write_image(image: &[u8], format: ImageFormat, out: impl Write) -> io::Result<()> {
+    match format {
+        ImageFormat::Binary => write_image_binary(image, out),
+        ImageFormat::Text => write_image_text(image, out),
+        ImageFormat::IntelHex => write_intel_hex(image, out),
+        ImageFormat::SRecord => write_srecords(image, out),
+    }
+}
+
+/// The address and bytes of each aligned block of `image` holding something other than zeros
+fn populated(image: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    image
+        .chunks(RECORD_SIZE)
+        .enumerate()
+        .filter(|(_, chunk)| chunk.iter().any(|&byte| byte != 0))
+        .map(|(i, chunk)| ((i * RECORD_SIZE) as u16, chunk))
+}
+
+/// Writes the populated parts of an image as Intel HEX data records, followed by an end of file
+/// record. Every address fits in 16 bits, so no extended address records are needed
+pub fn write_intel_hex(image: &[u8], mut out: impl Write) -> io::Result<()> {
+    for (addr, data) in populated(image) {
+        let mut record = vec![data.len() as u8];
+        record.extend(addr.to_be_bytes());
+        record.push(0x00);
+        record.extend(data);
+        writeln!(out, ":{}", hex_record(&record, intel_hex_checksum(&record)))?;
+    }
+    writeln!(out, ":00000001FF")
+}
+
+/// Writes the populated parts of an image as S1 records, between an S0 header and an S5 record
+/// count and S9 terminator
+pub fn write_srecords(image: &[u8], mut out: impl Write) -> io::Result<()> {
+    let mut write = |kind: char, addr: u16, data: &[u8]| {
+        let mut record = vec![data.len() as u8 + 3];
+        record.extend(addr.to_be_bytes());
+        record.extend(data);
+        writeln!(
+            out,
+            "S{kind}{}",
+            hex_record(&record, srecord_checksum(&record))
+        )
+    };
+    write('0', 0, b"HDR")?;
+    let mut count = 0u16;
+    for (addr, data) in populated(image) {
+        write('1', addr, data)?;
+        count += 1;
+    }
+    write('5', count, &[])?;
+    write('9', 0, &[])
+}
+
+fn hex_record(record: &[u8], checksum: u8) -> String {
+    record
+        .iter()
+        .chain([&checksum])
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+fn intel_hex_checksum(record: &[u8]) -> u8 {
+    record
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg()
+}
+
+fn srecord_checksum(record: &[u8]) -> u8 {
+    !record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Decodes the hex digits of a record, which follow its start code
+fn parse_hex_bytes(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Copies `data` into `image` at `addr`, growing it as needed, as long as it stays in memory
+fn place(image: &mut Vec<u8>, addr: usize, data: &[u8]) -> Result<(), String> {
+    let end = addr + data.len();
+    if end > MEMORY_SIZE {
+        return Err(format!(
+            "{} bytes at 0x{addr:X} run past the end of memory",
+            data.len()
+        ));
+    }
+    if image.len() < end {
+        image.resize(end, 0);
+    }
+    image[addr..end].copy_from_slice(data);
+    Ok(())
+}
+
+fn parse_intel_hex(text: &str) -> io::Result<Vec<u8>> {
+    let mut image = vec![];
+    // Set by extended segment and linear address records
+    let mut base = 0;
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| invalid(format!("line {}: {message}", number + 1));
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .and_then(parse_hex_bytes)
+            .filter(|record| record.len() >= 5 && record.len() == 5 + record[0] as usize)
+            .ok_or_else(|| error(format!("bad Intel HEX record {line:?}")))?;
+        let (body, checksum) = record.split_at(record.len() - 1);
+        if intel_hex_checksum(body) != checksum[0] {
+            return Err(error(format!(
+                "checksum is 0x{:02X}, expected 0x{:02X}",
+                checksum[0],
+                intel_hex_checksum(body)
+            )));
+        }
+        let addr = u16::from_be_bytes([body[1], body[2]]) as usize;
+        let data = &body[4..];
+        match body[3] {
+            0x00 => place(&mut image, base + addr, data).map_err(error)?,
+            0x01 => return Ok(image),
+            0x02 if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4
+            }
+            0x04 if data.len() == 2 => {
+                base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16
+            }
+            // Start addresses do not affect memory
+            0x03 | 0x05 => {}
+            kind => return Err(error(format!("unsupported record type 0x{kind:02X}"))),
+        }
+    }
+    Err(invalid("Intel HEX has no end of file record".to_owned()))
+}
+
+fn parse_srecords(text: &str) -> io::Result<Vec<u8>> {
+    let mut image = vec![];
+    let mut count = 0;
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| invalid(format!("line {}: {message}", number + 1));
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bad = || error(format!("bad S-record {line:?}"));
+        let kind = line.strip_prefix('S').and_then(|line| line.chars().next());
+        let record = line
+            .get(2..)
+            .and_then(parse_hex_bytes)
+            .filter(|record| !record.is_empty() && record.len() == 1 + record[0] as usize)
+            .ok_or_else(bad)?;
+        let (body, checksum) = record.split_at(record.len() - 1);
+        if srecord_checksum(body) != checksum[0] {
+            return Err(error(format!(
+                "checksum is 0x{:02X}, expected 0x{:02X}",
+                checksum[0],
+                srecord_checksum(body)
+            )));
+        }
+        let address_size = match kind {
+            Some('0' | '1' | '5' | '9') => 2,
+            Some('2' | '6' | '8') => 3,
+            Some('3' | '7') => 4,
+            _ => return Err(bad()),
+        };
+        if body.len() < 1 + address_size {
+            return Err(bad());
+        }
+        let addr = body[1..1 + address_size]
+            .iter()
+            .fold(0, |addr, &byte| addr << 8 | byte as usize);
+        let data = &body[1 + address_size..];
+        match kind {
+            Some('1' | '2' | '3') => {
+                place(&mut image, addr, data).map_err(error)?;
+                count += 1;
+            }
+            Some('5' | '6') if addr != count => {
+                return Err(error(format!("record count is {addr}, but found {count}")))
+            }
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_mem_in() {
+        let text = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/mem_in.txt"));
+        let image = read_image(text.unwrap().as_bytes()).unwrap();
+        assert_eq!(image[..], include!("../mem_in.txt")[..]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let image: Vec<u8> = (0..=40).collect();
+
+        let mut text = vec![];
+        write_image_text(&image, &mut text).unwrap();
+        assert!(String::from_utf8(text.clone())
+            .unwrap()
+            .starts_with("[\n0x00, 0x01, "));
+        assert_eq!(read_image(&text[..]).unwrap(), image);
+
+        let mut binary = vec![];
+        write_image_binary(&image, &mut binary).unwrap();
+        assert_eq!(read_image(&binary[..]).unwrap(), image);
+    }
+
+    /// Two separate populated blocks, with a zero inside the first
+    fn sparse_image() -> Vec<u8> {
+        let mut image = vec![0; 0x1012];
+        image[..3].copy_from_slice(&[0x0C, 0x00, 0x10]);
+        image[0x1010..].copy_from_slice(&[0xAB, 0xCD]);
+        image
+    }
+
+    #[test]
+    fn writes_intel_hex() {
+        let mut hex = vec![];
+        write_image(&sparse_image(), ImageFormat::IntelHex, &mut hex).unwrap();
+        assert_eq!(
+            String::from_utf8(hex.clone()).unwrap(),
+            ":100000000C001000000000000000000000000000D4\n\
+             :02101000ABCD66\n\
+             :00000001FF\n"
+        );
+        assert_eq!(read_image(&hex[..]).unwrap(), sparse_image());
+    }
+
+    #[test]
+    fn writes_srecords() {
+        let mut srec = vec![];
+        write_image(&sparse_image(), ImageFormat::SRecord, &mut srec).unwrap();
+        assert_eq!(
+            String::from_utf8(srec.clone()).unwrap(),
+            "S00600004844521B\n\
+             S11300000C001000000000000000000000000000D0\n\
+             S1051010ABCD62\n\
+             S5030002FA\n\
+             S9030000FC\n"
+        );
+        assert_eq!(read_image(&srec[..]).unwrap(), sparse_image());
+    }
+
+    #[test]
+    fn validates_records() {
+        let hex = |text: &[u8]| read_image_as(text, ImageFormat::IntelHex);
+        let err = hex(b":0100000019E6\n:0100010019E6\n:00000001FF\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: checksum is 0xE6, expected 0xE5");
+        let err = hex(b":02FFFF001919CE\n:00000001FF\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: 2 bytes at 0xFFFF run past the end of memory"
+        );
+        assert!(hex(b":0100000019E6\n").is_err());
+
+        let srec = |text: &[u8]| read_image_as(text, ImageFormat::SRecord);
+        let err = srec(b"S1040000191A\nS5030002FA\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: checksum is 0x1A, expected 0xE2");
+        let err = srec(b"S104000019E2\nS5030002FA\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: record count is 2, but found 1");
+        let err = srec(b"S2050100001ADF\n").unwrap_err();
+        assert!(err.to_string().contains("past the end of memory"), "{err}");
+    }
+
+    #[test]
+    fn picks_formats_from_paths() {
+        assert_eq!(ImageFormat::from_path("mem_out.txt"), ImageFormat::Text);
+        assert_eq!(ImageFormat::from_path("out.HEX"), ImageFormat::IntelHex);
+        assert_eq!(ImageFormat::from_path("a.b/out.s19"), ImageFormat::SRecord);
+        assert_eq!(ImageFormat::from_path("mem_out.bin"), ImageFormat::Binary);
+        assert_eq!(ImageFormat::from_path("mem_out"), ImageFormat::Binary);
+        assert_eq!(ImageFormat::from_extension("mem_out"), None);
+    }
+
+    #[test]
+    fn reads_raw_bytes_that_look_like_text() {
+        // A tab, then bytes that happen to spell an S-record start code
+        let raw = [0x09, 0x53, 0x19];
+        assert_eq!(read_image(&raw[..]).unwrap(), raw);
+        let raw = [b'S', 0x19, 0x00];
+        assert_eq!(read_image(&raw[..]).unwrap(), raw);
+        let raw = b":0C";
+        assert_eq!(read_image(&raw[..]).unwrap(), raw);
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let text = |text: &[u8]| read_image_as(text, ImageFormat::Text);
+        let err = text(b"[\n0x01, 0x02,\n0x03, 0xZZ\n]").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 3"), "{err}");
+
+        assert!(text(b"[0x01, 0x02").is_err());
+        assert!(text(&[0xFF]).is_err());
+        assert!(read_image(&vec![0; MEMORY_SIZE + 1][..]).is_err());
+    }
+}
@@ -1,28 +1,395 @@
+mod assembler;
+mod cache;
+mod classify;
 mod computer;
+#[cfg(test)]
+mod conformance;
+mod debugger;
+mod diff;
+mod disassembler;
+mod encoder;
+mod error;
+mod event;
+mod gdb;
+mod history;
+mod image;
 mod instruction;
+mod memory;
 mod parser;
+mod replacement;
+mod snapshot;
+mod sweep;
+mod timing;
+mod trace;
+mod watch;
 
+pub use assembler::*;
+pub use cache::*;
+pub use classify::*;
 pub use computer::*;
+pub use debugger::*;
+pub use diff::*;
+pub use disassembler::*;
+pub use encoder::*;
+pub use error::*;
+pub use event::*;
+pub use gdb::*;
+pub use image::*;
 pub use instruction::*;
+pub use memory::*;
 pub use parser::*;
+pub use replacement::*;
+pub use snapshot::*;
+pub use sweep::*;
+pub use timing::*;
+pub use trace::*;
+pub use watch::*;
 
+const USAGE: &str =
+    "usage: reverge_of_the_cache [--events human|json] [--trace <file>] [--expected <file>]
+                            [--output <file>] [--snapshot <file>] [--watch <watchpoint>]...
+                            <image>
+       reverge_of_the_cache --replay [<cache options>] <file>
+       reverge_of_the_cache --sweep [<grid options>] [<cache options>] <image> <file>
+       reverge_of_the_cache --disassemble <image>
+       reverge_of_the_cache --debug <image>
+       reverge_of_the_cache --gdb <port|stdio> <image>";
+
+/// Images are read as a hex array like mem_in.txt, Intel HEX, S-records or raw bytes. The format
+/// is picked by [`ImageFormat::from_extension`], or by [`read_image`] if the extension does not
+/// name one. Anywhere an image is run, a snapshot can be given instead to carry on from exactly
+/// where it was saved, see [`write_snapshot`]
+///
+/// Usage:
+///   `reverge_of_the_cache [--events human|json] [--trace <file>] [--expected <file>]
+///   [--output <file>] [--snapshot <file>] [--watch <watchpoint>]... <image>` runs `image`,
+///   optionally printing every execution event and recording every memory access to `file`.
+///   Traces ending in `.txt` are written as text, anything else as binary. Every access matching
+///   a watchpoint is printed, see [`Watchpoint`]'s `FromStr` impl for their syntax. Memory is
+///   written to the output file afterwards, in the format [`ImageFormat::from_path`] picks from
+///   its extension, defaulting to mem_out.bin. A snapshot of the machine where it stopped is
+///   saved to the snapshot file if one is given. If an expected image is given, any differences
+///   from it are printed and the exit code is 1
+///   `reverge_of_the_cache --replay [<cache options>] <file>` runs a recorded trace through a
+///   cache hierarchy without executing anything, see [`parse_hierarchy`] for the options
+///   `reverge_of_the_cache --sweep [<grid options>] [<cache options>] <image> <file>` runs
+///   `image` under a grid of cache configurations, writing a table of results as json if `file`
+///   ends in `.json` or csv otherwise, see [`parse_sweep_grid`] for the options. Each run is
+///   cut short after an instruction limit
+///   `reverge_of_the_cache --disassemble <image>` prints a listing of `image`
+///   `reverge_of_the_cache --debug <image>` steps through `image` interactively, type `help` at
+///   the prompt for a list of commands
+///   `reverge_of_the_cache --gdb <port|stdio> <image>` serves `image` to gdb over the remote
+///   serial protocol, either on a local tcp port or over stdin and stdout
 fn main() {
-    let mut memory = [0u8; MEMORY_SIZE];
-    let initial_memory = include!("../mem_in.txt");
-    (&mut memory[..initial_memory.len()]).copy_from_slice(&initial_memory);
-    std::fs::write("mem_in.bin", initial_memory).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["--replay", ref options @ .., path] => {
+            // Building the hierarchy panics if the levels do not fit together
+            let config = parse_hierarchy(options).and_then(|config| match config.check() {
+                Ok(()) => Ok(config),
+                Err(err) => Err(err.to_owned()),
+            });
+            let config = config.unwrap_or_else(|err| {
+                eprintln!("{err}");
+                std::process::exit(2);
+            });
+            let file = std::fs::File::open(path).unwrap();
+            let trace = read_trace(file).unwrap();
+            let system = replay_trace(&trace, config);
+            println!("{}", system.report());
+            return;
+        }
+        ["--sweep", ref options @ .., image, path] => {
+            let (grid, instruction_limit) = parse_sweep_grid(options).unwrap_or_else(|err| {
+                eprintln!("{err}");
+                std::process::exit(2);
+            });
+            let memory = load_memory(image);
+            let results = sweep(&memory, &grid, instruction_limit);
+            let file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+            if path.ends_with(".json") {
+                write_sweep_json(&results, file).unwrap();
+            } else {
+                write_sweep_csv(&results, file).unwrap();
+            }
+            return;
+        }
+        ["--debug", image] => {
+            let computer = load_computer(image);
+            Debugger::new(computer)
+                .repl(std::io::stdin().lock(), std::io::stdout())
+                .unwrap();
+            return;
+        }
+        ["--gdb", "stdio", image] => {
+            let computer = load_computer(image);
+            GdbStub::new(computer)
+                .serve(std::io::stdin().lock(), std::io::stdout())
+                .unwrap();
+            return;
+        }
+        ["--gdb", port, image] => {
+            let computer = load_computer(image);
+            let listener = std::net::TcpListener::bind((
+                "127.0.0.1",
+                port.parse().unwrap_or_else(|_| usage()),
+            ))
+            .unwrap();
+            eprintln!("waiting for gdb on {}", listener.local_addr().unwrap());
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(computer)
+                .serve(stream.try_clone().unwrap(), stream)
+                .unwrap();
+            return;
+        }
+        ["--disassemble", image] => {
+            for line in disassemble(&load_image(image), 0) {
+                println!("{line}");
+            }
+            return;
+        }
+        _ => {}
+    }
 
-    let mut computer = Computer::new(memory);
-    computer.run();
+    let mut image_path = None;
+    let mut trace_path = None;
+    let mut expected_path = None;
+    let mut snapshot_path = None;
+    let mut output_path = "mem_out.bin".to_owned();
+    let mut events = None;
+    let mut watchpoints = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") && image_path.is_none() {
+            image_path = Some(arg);
+            continue;
+        }
+        match (arg.as_str(), args.next()) {
+            ("--trace", Some(path)) => trace_path = Some(path),
+            ("--expected", Some(path)) => expected_path = Some(path),
+            ("--output", Some(path)) => output_path = path,
+            ("--snapshot", Some(path)) => snapshot_path = Some(path),
+            ("--watch", Some(watchpoint)) => match watchpoint.parse::<Watchpoint>() {
+                Ok(watchpoint) => watchpoints.push(watchpoint),
+                Err(err) => {
+                    eprintln!("{err}");
+                    usage();
+                }
+            },
+            ("--events", Some(format)) if format == "human" || format == "json" => {
+                events = Some(format)
+            }
+            _ => usage(),
+        }
+    }
+    let Some(image_path) = image_path else {
+        usage();
+    };
+
+    let mut computer = load_computer(&image_path);
+    if trace_path.is_some() {
+        computer.record_trace();
+    }
+    if expected_path.is_some() {
+        computer.record_writers();
+    }
+    match events.as_deref() {
+        Some("human") => computer.set_observer(HumanReadable::new(std::io::stdout())),
+        Some("json") => computer.set_observer(MachineReadable::new(std::io::stdout())),
+        _ => {}
+    }
+    for watchpoint in watchpoints {
+        computer.add_watchpoint(watchpoint);
+    }
+    // Watchpoints only report who touched memory, so keep going after each one
+    loop {
+        match computer.run() {
+            Ok(StopReason::Watchpoint) => println!("{}", computer.watch_hit().unwrap()),
+            Ok(_) => break,
+            Err(err) => {
+                println!("stopped: {err}");
+                break;
+            }
+        }
+    }
+    // Events usually go to stdout, so if they could not be written the report cannot be either
+    if let Some(err) = computer.observer_error() {
+        eprintln!("failed to write events: {err}");
+        std::process::exit(1);
+    }
+    println!("{}", computer.memory_system().report());
+    println!("{}", computer.timing());
+
+    if let Some(path) = snapshot_path {
+        let file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        write_snapshot(&computer, file).unwrap();
+    }
+
+    if let Some(path) = trace_path {
+        let trace = computer.trace().unwrap();
+        let file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        if path.ends_with(".txt") {
+            write_trace_text(trace, file).unwrap();
+        } else {
+            write_trace_binary(trace, file).unwrap();
+        }
+    }
 
-    let expected_memory = include!("../mem_out.txt");
     let actual_memory = computer.memory();
-    std::fs::write("mem_out.bin", actual_memory).unwrap();
-    for i in 0..expected_memory.len() {
-        let expected = expected_memory[i];
-        let actual = actual_memory[i];
-        if expected != actual {
-            println!("{i:X} differs expected {expected:X}, was {actual:}");
+    let file = std::io::BufWriter::new(std::fs::File::create(&output_path).unwrap());
+    write_image(actual_memory, ImageFormat::from_path(&output_path), file).unwrap();
+
+    if let Some(path) = expected_path {
+        let expected_memory = load_image(&path);
+        if !diff_memory(&expected_memory, actual_memory).is_empty() {
+            let writers = |addr| computer.last_writer(addr);
+            write_memory_diff(&expected_memory, actual_memory, writers, std::io::stdout()).unwrap();
+            std::process::exit(1);
         }
     }
 }
+
+/// Builds the cache hierarchy described by `options`, given as `--l1 <cache|none>`,
+/// `--l1i <cache> --l1d <cache>` for split L1 caches, `--lower <cache>` once for each level
+/// below L1, `--inclusion inclusive|exclusive|nine` and `--memory-latency <cycles>`. Caches are
+/// written as [`CacheConfig`]'s `FromStr` impl expects, and anything left out is taken from
+/// [`HierarchyConfig::default`]. The levels are not checked against each other, see
+/// [`HierarchyConfig::check`]
+fn parse_hierarchy(options: &[&str]) -> Result<HierarchyConfig, String> {
+    let mut config = HierarchyConfig::default();
+    let (mut instruction, mut data) = (None, None);
+    for pair in options.chunks(2) {
+        let &[option, value] = pair else {
+            return Err(format!("`{}` needs a value", pair[0]));
+        };
+        match option {
+            "--l1" if value == "none" => config.l1 = L1Config::None,
+            "--l1" => config.l1 = L1Config::Unified(value.parse()?),
+            "--l1i" => instruction = Some(value.parse()?),
+            "--l1d" => data = Some(value.parse()?),
+            "--lower" => config.lower.push(value.parse()?),
+            "--inclusion" => {
+                config.inclusion = match value {
+                    "inclusive" => Inclusion::Inclusive,
+                    "exclusive" => Inclusion::Exclusive,
+                    "nine" => Inclusion::Nine,
+                    _ => return Err(format!("unknown inclusion policy `{value}`")),
+                }
+            }
+            "--memory-latency" => {
+                config.memory_latency = value
+                    .parse()
+                    .map_err(|_| format!("bad memory latency `{value}`"))?
+            }
+            _ => return Err(format!("unknown cache option `{option}`")),
+        }
+    }
+    match (instruction, data) {
+        (Some(instruction), Some(data)) => config.l1 = L1Config::Split { instruction, data },
+        (None, None) => {}
+        _ => return Err("split L1 caches need both --l1i and --l1d".to_owned()),
+    }
+
+    Ok(config)
+}
+
+/// Builds a sweep grid from `options`, given as `--sizes`, `--line-sizes`, `--ways` and
+/// `--replacements` followed by a comma separated list, and `--level <level>` for the level of
+/// the hierarchy to sweep. Every other option describes the hierarchy being swept through, see
+/// [`parse_hierarchy`]. Anything left out is taken from [`SweepGrid::default`]. Also returns the
+/// most instructions each run may execute, given by `--limit <instructions>` and
+/// [`DEFAULT_SWEEP_INSTRUCTION_LIMIT`] by default
+fn parse_sweep_grid(options: &[&str]) -> Result<(SweepGrid, u64), String> {
+    fn list<T: std::str::FromStr>(values: &str) -> Result<Vec<T>, String> {
+        values
+            .split(',')
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("bad value `{value}` in sweep grid"))
+            })
+            .collect()
+    }
+
+    let mut grid = SweepGrid::default();
+    let mut instruction_limit = DEFAULT_SWEEP_INSTRUCTION_LIMIT;
+    let mut hierarchy = vec![];
+    for pair in options.chunks(2) {
+        match *pair {
+            ["--sizes", values] => grid.sizes = list(values)?,
+            ["--line-sizes", values] => grid.line_sizes = list(values)?,
+            ["--ways", values] => grid.ways = list(values)?,
+            ["--replacements", values] => {
+                grid.replacements = values
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?
+            }
+            ["--level", level] => {
+                grid.level = level
+                    .parse()
+                    .map_err(|_| format!("bad sweep level `{level}`"))?
+            }
+            ["--limit", limit] => {
+                instruction_limit = limit
+                    .parse()
+                    .map_err(|_| format!("bad instruction limit `{limit}`"))?
+            }
+            _ => hierarchy.extend_from_slice(pair),
+        }
+    }
+    grid.hierarchy = parse_hierarchy(&hierarchy)?;
+    if grid.configs().is_empty() {
+        return Err("the sweep grid has no cache configurations that fit the hierarchy".to_owned());
+    }
+    Ok((grid, instruction_limit))
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+/// Reads `data`, loaded from `path`, as an image in the format the extension of `path` names,
+/// or in whatever format [`read_image`] finds if it names none
+fn decode_image(path: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match ImageFormat::from_extension(path) {
+        Some(format) => read_image_as(data, format),
+        None => read_image(data),
+    }
+}
+
+/// Reads the image at `path`, exiting with a message if it cannot be read
+fn load_image(path: &str) -> Vec<u8> {
+    let image = std::fs::read(path).and_then(|data| decode_image(path, &data));
+    image.unwrap_or_else(|err| {
+        eprintln!("failed to read {path}: {err}");
+        std::process::exit(1);
+    })
+}
+
+/// Reads the image at `path` into the start of an otherwise zeroed memory
+fn load_memory(path: &str) -> [u8; MEMORY_SIZE] {
+    zero_extend(&load_image(path))
+}
+
+/// Restores the snapshot at `path`, or loads the image there in front of the default cache,
+/// exiting with a message if neither can be read
+fn load_computer(path: &str) -> Computer {
+    let computer = std::fs::read(path).and_then(|data| match is_snapshot(&data) {
+        true => read_snapshot(&data[..]),
+        false => decode_image(path, &data)
+            .map(|image| Computer::with_cache(zero_extend(&image), CacheConfig::default())),
+    });
+    computer.unwrap_or_else(|err| {
+        eprintln!("failed to read {path}: {err}");
+        std::process::exit(1);
+    })
+}
+
+fn zero_extend(image: &[u8]) -> [u8; MEMORY_SIZE] {
+    let mut memory = [0u8; MEMORY_SIZE];
+    memory[..image.len()].copy_from_slice(image);
+    memory
+}
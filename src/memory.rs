@@ -0,0 +1,776 @@
+//! Main memory along with the cache hierarchy in front of it
+
+use crate::snapshot::{invalid, SnapshotReader, SnapshotWriter};
+use crate::{Backing, Cache, CacheConfig, CacheStats, MissBreakdown, MEMORY_SIZE};
+use std::fmt;
+use std::io;
+
+/// Why the cpu is accessing memory
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccessSource {
+    /// Fetching an opcode or one of its immediate operands through pc
+    Instruction,
+    /// Reading or writing an operand
+    Data,
+}
+
+/// How the contents of a cache level relate to the levels above it
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Inclusion {
+    /// Every line in a level is also held by each level below it. When a lower level evicts a
+    /// line, the levels above it drop their copies too
+    Inclusive,
+    /// A line lives in at most one level. Hits in a lower level move the line up, and lines
+    /// evicted from a level move down into the next one
+    Exclusive,
+    /// Non inclusive non exclusive. Misses fill every level they pass through, but evicting a
+    /// line from one level does not affect the others
+    #[default]
+    Nine,
+}
+
+/// The first level of cache the cpu talks to
+pub enum L1 {
+    None,
+    /// One cache shared by instruction and data accesses
+    Unified(Cache),
+    /// Harvard style caches, with instruction fetches and data accesses kept apart
+    Split {
+        instruction: Cache,
+        data: Cache,
+    },
+}
+
+impl L1 {
+    fn caches_mut(&mut self) -> Vec<&mut Cache> {
+        match self {
+            L1::None => vec![],
+            L1::Unified(cache) => vec![cache],
+            L1::Split { instruction, data } => vec![instruction, data],
+        }
+    }
+}
+
+/// Describes the caches an [`L1`] should be built with
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum L1Config {
+    None,
+    Unified(CacheConfig),
+    Split {
+        instruction: CacheConfig,
+        data: CacheConfig,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HierarchyConfig {
+    pub l1: L1Config,
+    /// The levels below L1, starting with L2. Every level must use the same line size, and
+    /// there can be no lower levels without an L1
+    pub lower: Vec<CacheConfig>,
+    pub inclusion: Inclusion,
+    /// Cycles it takes to read a line from main memory
+    pub memory_latency: u64,
+}
+
+impl Default for HierarchyConfig {
+    /// A single default L1 cache in front of 100 cycle memory
+    fn default() -> Self {
+        Self {
+            l1: L1Config::Unified(CacheConfig::default()),
+            lower: vec![],
+            inclusion: Inclusion::Nine,
+            memory_latency: 100,
+        }
+    }
+}
+
+impl HierarchyConfig {
+    /// Checks that the levels fit together, without checking each cache's own geometry
+    pub fn check(&self) -> Result<(), &'static str> {
+        let l1 = match &self.l1 {
+            L1Config::None if !self.lower.is_empty() => {
+                return Err("lower levels require an L1 cache")
+            }
+            L1Config::None => vec![],
+            L1Config::Unified(config) => vec![config],
+            L1Config::Split { instruction, data } => vec![instruction, data],
+        };
+        let line_sizes: Vec<_> = l1
+            .into_iter()
+            .chain(&self.lower)
+            .map(|config| config.line_size)
+            .collect();
+        match line_sizes.windows(2).all(|pair| pair[0] == pair[1]) {
+            true => Ok(()),
+            false => Err("every cache level must use the same line size"),
+        }
+    }
+}
+
+pub struct MemorySystem {
+    memory: [u8; MEMORY_SIZE],
+    l1: L1,
+    /// L2 onwards
+    lower: Vec<Cache>,
+    inclusion: Inclusion,
+    memory_latency: u64,
+    /// Number of reads and writes made by the cpu
+    accesses: u64,
+    /// Number of accesses that had to go all the way to main memory
+    memory_accesses: u64,
+    /// Total latency of every access so far
+    cycles: u64,
+    /// Lines evicted from an inclusive lower level that L1 still has to drop
+    invalidations: Vec<u16>,
+}
+
+impl MemorySystem {
+    /// Memory without any caches
+    pub fn new(memory: [u8; MEMORY_SIZE]) -> Self {
+        Self::with_config(
+            memory,
+            HierarchyConfig {
+                l1: L1Config::None,
+                ..HierarchyConfig::default()
+            },
+        )
+    }
+
+    /// Memory behind one cache that handles every access
+    pub fn unified(memory: [u8; MEMORY_SIZE], config: CacheConfig) -> Self {
+        Self::with_config(
+            memory,
+            HierarchyConfig {
+                l1: L1Config::Unified(config),
+                ..HierarchyConfig::default()
+            },
+        )
+    }
+
+    /// Memory behind separate instruction and data caches
+    pub fn split(memory: [u8; MEMORY_SIZE], instruction: CacheConfig, data: CacheConfig) -> Self {
+        Self::with_config(
+            memory,
+            HierarchyConfig {
+                l1: L1Config::Split { instruction, data },
+                ..HierarchyConfig::default()
+            },
+        )
+    }
+
+    /// Memory behind an arbitrary cache hierarchy.
+    ///
+    /// Panics if the levels use different line sizes, or if there are lower levels without an
+    /// L1
+    pub fn with_config(memory: [u8; MEMORY_SIZE], config: HierarchyConfig) -> Self {
+        if let Err(err) = config.check() {
+            panic!("{err}");
+        }
+        let l1 = match config.l1 {
+            L1Config::None => L1::None,
+            L1Config::Unified(config) => L1::Unified(Cache::new(config)),
+            L1Config::Split { instruction, data } => L1::Split {
+                instruction: Cache::new(instruction),
+                data: Cache::new(data),
+            },
+        };
+
+        Self {
+            memory,
+            l1,
+            lower: config.lower.into_iter().map(Cache::new).collect(),
+            inclusion: config.inclusion,
+            memory_latency: config.memory_latency,
+            accesses: 0,
+            memory_accesses: 0,
+            cycles: 0,
+            invalidations: vec![],
+        }
+    }
+
+    /// Main memory. With a write back cache this can be stale until [`MemorySystem::flush`] is
+    /// called
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Main memory, for changing it directly. Dirty lines are flushed and every cache is
+    /// emptied first, so later accesses see whatever is written here
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.flush();
+        for cache in self.l1.caches_mut() {
+            cache.invalidate_all();
+        }
+        for cache in &mut self.lower {
+            cache.invalidate_all();
+        }
+        &mut self.memory
+    }
+
+    /// The current value at `addr`, taken from the highest level holding it. Unlike
+    /// [`MemorySystem::read`] this does not count as an access or change any cache state
+    pub fn peek(&self, addr: u16) -> u8 {
+        // Writes reach the data cache first, so its copy is always the newest
+        let l1 = match &self.l1 {
+            L1::None => vec![],
+            L1::Unified(cache) => vec![cache],
+            L1::Split { instruction, data } => vec![data, instruction],
+        };
+        l1.into_iter()
+            .chain(&self.lower)
+            .find_map(|cache| cache.peek(addr))
+            .unwrap_or(self.memory[addr as usize])
+    }
+
+    /// Changes the value at `addr` in main memory and every cache holding it, without counting
+    /// an access or changing any other cache state
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        for cache in self.l1.caches_mut() {
+            cache.overwrite(addr, value);
+        }
+        for cache in &mut self.lower {
+            cache.overwrite(addr, value);
+        }
+        self.memory[addr as usize] = value;
+    }
+
+    pub fn l1(&self) -> &L1 {
+        &self.l1
+    }
+
+    /// The configuration this hierarchy was built with
+    pub fn config(&self) -> HierarchyConfig {
+        HierarchyConfig {
+            l1: match &self.l1 {
+                L1::None => L1Config::None,
+                L1::Unified(cache) => L1Config::Unified(*cache.config()),
+                L1::Split { instruction, data } => L1Config::Split {
+                    instruction: *instruction.config(),
+                    data: *data.config(),
+                },
+            },
+            lower: self.lower.iter().map(|cache| *cache.config()).collect(),
+            inclusion: self.inclusion,
+            memory_latency: self.memory_latency,
+        }
+    }
+
+    /// Saves the configuration followed by main memory, the access counters and every cache
+    pub(crate) fn save_snapshot(&self, out: &mut SnapshotWriter) {
+        let config = self.config();
+        match &config.l1 {
+            L1Config::None => out.u8(0),
+            L1Config::Unified(config) => {
+                out.u8(1);
+                out.cache_config(config);
+            }
+            L1Config::Split { instruction, data } => {
+                out.u8(2);
+                out.cache_config(instruction);
+                out.cache_config(data);
+            }
+        }
+        out.usize(config.lower.len());
+        for config in &config.lower {
+            out.cache_config(config);
+        }
+        out.u8(match config.inclusion {
+            Inclusion::Inclusive => 0,
+            Inclusion::Exclusive => 1,
+            Inclusion::Nine => 2,
+        });
+        out.u64(config.memory_latency);
+
+        out.bytes(&self.memory);
+        out.u64(self.accesses);
+        out.u64(self.memory_accesses);
+        out.u64(self.cycles);
+        for (_, cache) in self.caches() {
+            cache.save_snapshot(out);
+        }
+    }
+
+    /// Rebuilds a hierarchy saved by [`MemorySystem::save_snapshot`]
+    pub(crate) fn load_snapshot(input: &mut SnapshotReader) -> io::Result<Self> {
+        let l1 = match input.u8()? {
+            0 => L1Config::None,
+            1 => L1Config::Unified(input.cache_config()?),
+            2 => L1Config::Split {
+                instruction: input.cache_config()?,
+                data: input.cache_config()?,
+            },
+            kind => return Err(invalid(format!("unknown L1 kind {kind} in snapshot"))),
+        };
+        // Every level's config takes more than a byte
+        let lower = (0..input.usize(input.remaining())?)
+            .map(|_| input.cache_config())
+            .collect::<io::Result<Vec<_>>>()?;
+        let inclusion = match input.u8()? {
+            0 => Inclusion::Inclusive,
+            1 => Inclusion::Exclusive,
+            2 => Inclusion::Nine,
+            inclusion => return Err(invalid(format!("unknown inclusion {inclusion}"))),
+        };
+        let config = HierarchyConfig {
+            l1,
+            lower,
+            inclusion,
+            memory_latency: input.u64()?,
+        };
+        // Check what `with_config` would panic on
+        if let Err(err) = config.check() {
+            return Err(invalid(format!(
+                "snapshot has caches that do not fit: {err}"
+            )));
+        }
+
+        let memory = input.bytes(MEMORY_SIZE)?.try_into().unwrap();
+        let mut system = Self::with_config(memory, config);
+        system.accesses = input.u64()?;
+        system.memory_accesses = input.u64()?;
+        system.cycles = input.u64()?;
+        for cache in system.l1.caches_mut() {
+            cache.restore_snapshot(input)?;
+        }
+        for cache in &mut system.lower {
+            cache.restore_snapshot(input)?;
+        }
+        Ok(system)
+    }
+
+    /// Every cache along with a short name for it, from the top of the hierarchy down
+    pub fn caches(&self) -> Vec<(String, &Cache)> {
+        let l1 = match &self.l1 {
+            L1::None => vec![],
+            L1::Unified(cache) => vec![("L1".to_owned(), cache)],
+            L1::Split { instruction, data } => {
+                vec![("L1i".to_owned(), instruction), ("L1d".to_owned(), data)]
+            }
+        };
+        let lower = self
+            .lower
+            .iter()
+            .enumerate()
+            .map(|(i, cache)| (format!("L{}", i + 2), cache));
+        l1.into_iter().chain(lower).collect()
+    }
+
+    /// Per level statistics along with the average memory access time
+    pub fn report(&self) -> HierarchyReport {
+        HierarchyReport {
+            levels: self
+                .caches()
+                .into_iter()
+                .map(|(name, cache)| LevelReport {
+                    name,
+                    stats: *cache.stats(),
+                    latency: cache.config().latency,
+                    regions: cache.miss_regions(),
+                })
+                .collect(),
+            accesses: self.accesses,
+            memory_accesses: self.memory_accesses,
+            memory_latency: self.memory_latency,
+            cycles: self.cycles,
+        }
+    }
+
+    /// Total number of cycles spent waiting on memory. Every access is charged the latency of
+    /// each level it looked in, plus the memory latency if it went all the way to main memory
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Reads a byte through the cache responsible for `source`
+    pub fn read(&mut self, addr: u16, source: AccessSource) -> u8 {
+        self.accesses += 1;
+        let (l1, mut lower) = self.parts();
+        let cache = match (l1, source) {
+            (L1::None, _) => {
+                *lower.memory_accesses += 1;
+                *lower.cycles += lower.memory_latency;
+                return lower.memory[addr as usize];
+            }
+            (L1::Unified(cache), _) => cache,
+            (L1::Split { instruction, data }, AccessSource::Instruction) => {
+                // The data cache may be holding newer bytes for this line that were never
+                // written back
+                data.clean(&mut lower, addr);
+                instruction
+            }
+            (L1::Split { data, .. }, AccessSource::Data) => data,
+        };
+        *lower.cycles += cache.config().latency;
+        let (value, _) = cache.read(&mut lower, addr);
+        self.back_invalidate();
+        value
+    }
+
+    /// Writes a byte through the data cache
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.accesses += 1;
+        let (l1, mut lower) = self.parts();
+        let cache = match l1 {
+            L1::None => {
+                *lower.memory_accesses += 1;
+                *lower.cycles += lower.memory_latency;
+                lower.write_byte(addr, value);
+                return;
+            }
+            L1::Unified(cache) => cache,
+            L1::Split { instruction, data } => {
+                // Don't let the instruction cache execute stale code
+                instruction.invalidate(addr);
+                data
+            }
+        };
+        *lower.cycles += cache.config().latency;
+        cache.write(&mut lower, addr, value);
+        self.back_invalidate();
+    }
+
+    /// Writes any dirty cache lines back to main memory
+    pub fn flush(&mut self) {
+        let (l1, mut lower) = self.parts();
+        for cache in l1.caches_mut() {
+            cache.flush(&mut lower);
+        }
+        for level in 0..self.lower.len() {
+            let (cache, caches) = self.lower[level..].split_first_mut().unwrap();
+            cache.flush(&mut Lower {
+                caches,
+                memory: &mut self.memory,
+                inclusion: self.inclusion,
+                memory_latency: self.memory_latency,
+                memory_accesses: &mut self.memory_accesses,
+                cycles: &mut self.cycles,
+                invalidations: &mut self.invalidations,
+            });
+        }
+    }
+
+    /// Splits the hierarchy into L1 and everything below it
+    fn parts(&mut self) -> (&mut L1, Lower<'_>) {
+        let lower = Lower {
+            caches: &mut self.lower,
+            memory: &mut self.memory,
+            inclusion: self.inclusion,
+            memory_latency: self.memory_latency,
+            memory_accesses: &mut self.memory_accesses,
+            cycles: &mut self.cycles,
+            invalidations: &mut self.invalidations,
+        };
+        (&mut self.l1, lower)
+    }
+
+    /// Drops lines from L1 that an inclusive lower level evicted during the last access
+    fn back_invalidate(&mut self) {
+        let invalidations = std::mem::take(&mut self.invalidations);
+        let (l1, mut lower) = self.parts();
+        for addr in invalidations {
+            for cache in l1.caches_mut() {
+                if let Some((data, true)) = cache.take(addr) {
+                    lower.write_line(addr, &data);
+                }
+            }
+        }
+    }
+}
+
+/// The cache levels below L1 followed by main memory, as seen from the level directly above
+struct Lower<'a> {
+    caches: &'a mut [Cache],
+    memory: &'a mut [u8],
+    inclusion: Inclusion,
+    memory_latency: u64,
+    memory_accesses: &'a mut u64,
+    cycles: &'a mut u64,
+    invalidations: &'a mut Vec<u16>,
+}
+
+impl Lower<'_> {
+    /// Splits off the first level, returning it along with the levels below it
+    fn split(&mut self) -> Option<(&mut Cache, Lower<'_>)> {
+        let (cache, caches) = self.caches.split_first_mut()?;
+        let next = Lower {
+            caches,
+            memory: &mut *self.memory,
+            inclusion: self.inclusion,
+            memory_latency: self.memory_latency,
+            memory_accesses: &mut *self.memory_accesses,
+            cycles: &mut *self.cycles,
+            invalidations: &mut *self.invalidations,
+        };
+        Some((cache, next))
+    }
+}
+
+impl Backing for Lower<'_> {
+    fn read_line(&mut self, base: u16, data: &mut [u8]) -> bool {
+        let inclusion = self.inclusion;
+        let Some((cache, mut next)) = self.split() else {
+            *self.memory_accesses += 1;
+            *self.cycles += self.memory_latency;
+            return self.memory.read_line(base, data);
+        };
+        *next.cycles += cache.config().latency;
+        if let Some(line) = cache.probe(base) {
+            data.copy_from_slice(line);
+            return match inclusion {
+                // Move the line up, taking responsibility for any unwritten data with it
+                Inclusion::Exclusive => cache.take(base).unwrap().1,
+                Inclusion::Inclusive | Inclusion::Nine => false,
+            };
+        }
+
+        let dirty = next.read_line(base, data);
+        if inclusion == Inclusion::Exclusive {
+            return dirty;
+        }
+        // Levels further down may have evicted lines this level has to drop to stay inclusive
+        for i in 0..next.invalidations.len() {
+            let addr = next.invalidations[i];
+            if let Some((line, true)) = cache.take(addr) {
+                next.write_line(addr, &line);
+            }
+        }
+        let outcome = cache.insert(&mut next, base, data, dirty);
+        if let (Inclusion::Inclusive, Some(evicted)) = (inclusion, outcome.evicted) {
+            next.invalidations.push(evicted);
+        }
+        false
+    }
+
+    fn evict_line(&mut self, base: u16, data: &[u8], dirty: bool) {
+        if self.inclusion == Inclusion::Exclusive {
+            if let Some((cache, mut next)) = self.split() {
+                cache.insert(&mut next, base, data, dirty);
+                return;
+            }
+        }
+        if dirty {
+            self.write_line(base, data);
+        }
+    }
+
+    fn write_line(&mut self, base: u16, data: &[u8]) {
+        match self.split() {
+            Some((cache, mut next)) => {
+                if !cache.update_line(&mut next, base, data) {
+                    next.write_line(base, data);
+                }
+            }
+            None => self.memory.write_line(base, data),
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match self.split() {
+            Some((cache, mut next)) => {
+                if !cache.update_byte(&mut next, addr, value) {
+                    next.write_byte(addr, value);
+                }
+            }
+            None => self.memory.write_byte(addr, value),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LevelReport {
+    pub name: String,
+    pub stats: CacheStats,
+    pub latency: u64,
+    /// Misses broken down by the region of memory they fall in, keyed by the region's first
+    /// address
+    pub regions: Vec<(u16, MissBreakdown)>,
+}
+
+/// A summary of how the cache hierarchy performed
+#[derive(Clone, PartialEq, Debug)]
+pub struct HierarchyReport {
+    pub levels: Vec<LevelReport>,
+    /// Number of reads and writes made by the cpu
+    pub accesses: u64,
+    /// Number of accesses that had to go all the way to main memory
+    pub memory_accesses: u64,
+    pub memory_latency: u64,
+    /// Total cycles spent on memory accesses
+    pub cycles: u64,
+}
+
+impl HierarchyReport {
+    /// Average number of cycles each access spent looking through the hierarchy, charging every
+    /// level an access reached its latency. Stores written through or around a cache are
+    /// assumed to be buffered and are not charged beyond the first level
+    pub fn amat(&self) -> f64 {
+        match self.accesses {
+            0 => 0.0,
+            n => self.cycles as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for HierarchyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for level in &self.levels {
+            let stats = &level.stats;
+            writeln!(
+                f,
+                "{}: {} accesses, {} hits, {} misses, {} evictions, {} writebacks ({:.2}% hit rate)",
+                level.name,
+                stats.accesses(),
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.writebacks,
+                stats.hit_rate() * 100.0
+            )?;
+            writeln!(f, "  misses: {}", stats.miss_kinds)?;
+            for (start, misses) in &level.regions {
+                writeln!(f, "  misses in [0x{start:04X}]: {misses}")?;
+            }
+        }
+        writeln!(f, "memory: {} accesses", self.memory_accesses)?;
+        write!(f, "average memory access time: {:.2} cycles", self.amat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hierarchy(inclusion: Inclusion) -> MemorySystem {
+        let mut memory = [0; MEMORY_SIZE];
+        for (i, byte) in memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        MemorySystem::with_config(
+            memory,
+            HierarchyConfig {
+                // Direct mapped L1 with only two lines, in front of a four line L2
+                l1: L1Config::Unified(CacheConfig::new(4, 2, 1)),
+                lower: vec![CacheConfig::new(4, 1, 4).with_latency(10)],
+                inclusion,
+                memory_latency: 100,
+            },
+        )
+    }
+
+    fn stats(system: &MemorySystem, level: usize) -> (u64, u64) {
+        let stats = system.caches()[level].1.stats();
+        (stats.hits, stats.misses)
+    }
+
+    #[test]
+    fn split_caches_keep_separate_stats() {
+        let mut system = MemorySystem::split(
+            [0; MEMORY_SIZE],
+            CacheConfig::default(),
+            CacheConfig::default(),
+        );
+        system.read(0x00, AccessSource::Instruction);
+        system.read(0x01, AccessSource::Instruction);
+        system.write(0x2000, 0xAB);
+
+        assert_eq!(stats(&system, 0), (1, 1));
+        assert_eq!(stats(&system, 1), (0, 1));
+    }
+
+    #[test]
+    fn instruction_fetch_sees_data_stores() {
+        let mut system = MemorySystem::split(
+            [0; MEMORY_SIZE],
+            CacheConfig::default(),
+            CacheConfig::default(),
+        );
+        assert_eq!(system.read(0x10, AccessSource::Instruction), 0x00);
+        // Dirty in the write back data cache, and stale in the instruction cache
+        system.write(0x10, 0x19);
+        assert_eq!(system.read(0x10, AccessSource::Instruction), 0x19);
+    }
+
+    #[test]
+    fn l2_catches_l1_conflicts() {
+        let mut system = hierarchy(Inclusion::Nine);
+        // 0x00 and 0x08 map to the same L1 set
+        system.read(0x00, AccessSource::Data);
+        system.read(0x08, AccessSource::Data);
+        assert_eq!(system.read(0x00, AccessSource::Data), 0x00);
+
+        assert_eq!(stats(&system, 0), (0, 3));
+        assert_eq!(stats(&system, 1), (1, 2));
+        let report = system.report();
+        assert_eq!(report.memory_accesses, 2);
+        // 3 L1 lookups, 3 L2 lookups and 2 trips to memory over 3 accesses
+        assert_eq!(report.amat(), (3.0 + 30.0 + 200.0) / 3.0);
+    }
+
+    #[test]
+    fn exclusive_moves_lines_between_levels() {
+        let mut system = hierarchy(Inclusion::Exclusive);
+        system.read(0x00, AccessSource::Data);
+        // Misses fill only L1
+        assert_eq!(system.lower[0].peek(0x00), None);
+
+        // The conflicting line pushes 0x00 down into L2, where it hits and moves back up
+        system.read(0x08, AccessSource::Data);
+        assert_eq!(system.lower[0].peek(0x00), Some(0x00));
+        system.read(0x00, AccessSource::Data);
+        assert_eq!(system.lower[0].peek(0x00), None);
+        assert_eq!(system.lower[0].peek(0x08), Some(0x08));
+        assert_eq!(stats(&system, 1), (1, 2));
+    }
+
+    #[test]
+    fn inclusive_eviction_invalidates_l1() {
+        let mut system = hierarchy(Inclusion::Inclusive);
+        system.write(0x00, 0xAB);
+        // Fill the rest of the single L2 set without touching L1's set for 0x00
+        for addr in [0x04, 0x0C, 0x14] {
+            system.read(addr, AccessSource::Data);
+        }
+        // L2 evicts 0x00, so L1 has to give up its dirty copy
+        system.read(0x1C, AccessSource::Data);
+        assert_eq!(system.caches()[0].1.peek(0x00), None);
+        assert_eq!(system.memory()[0x00], 0xAB);
+    }
+
+    #[test]
+    fn flush_reaches_memory_through_every_level() {
+        for inclusion in [Inclusion::Inclusive, Inclusion::Exclusive, Inclusion::Nine] {
+            let mut system = hierarchy(inclusion);
+            for addr in 0..0x40 {
+                system.write(addr, !(addr as u8));
+            }
+            system.flush();
+            for addr in 0..0x40 {
+                assert_eq!(
+                    system.memory()[addr as usize],
+                    !(addr as u8),
+                    "{inclusion:?}"
+                );
+                assert_eq!(system.read(addr, AccessSource::Data), !(addr as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn peek_and_poke_see_cached_data() {
+        let mut system = hierarchy(Inclusion::Nine);
+        system.write(0x00, 0xAB);
+        let report = system.report();
+        assert_eq!(system.memory()[0x00], 0x00);
+        assert_eq!(system.peek(0x00), 0xAB);
+
+        system.poke(0x00, 0xCD);
+        system.poke(0x40, 0xEF);
+        assert_eq!(system.report(), report);
+        assert_eq!(system.read(0x00, AccessSource::Data), 0xCD);
+        assert_eq!(system.memory()[0x40], 0xEF);
+
+        system.memory_mut()[0x00] = 0x12;
+        assert_eq!(system.read(0x00, AccessSource::Data), 0x12);
+    }
+}
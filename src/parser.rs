@@ -3,10 +3,10 @@ use modular_bitfield::prelude::*;
 
 pub fn try_parse(opcode: u8) -> Option<Instruction> {
     Some(match opcode {
-        0b1000_000..=0b1111_1111 => {
+        0b1000_0000..=0b1111_1111 => {
             let func = MathFunction::from_bytes((opcode & 0b0111_0000) >> 4).ok()?;
             let dst = DstTarget::from_bytes((opcode & 0b0000_1100) >> 2).ok()?;
-            let src = SrcTarget::from_bytes((opcode & 0b0000_0011) >> 0).ok()?;
+            let src = SrcTarget::from_bytes(opcode & 0b0000_0011).ok()?;
             Instruction::Mathmatical { func, src, dst }
         }
         0b0000_0000..=0b0000_1111 => {
@@ -29,7 +29,7 @@ pub fn try_parse(opcode: u8) -> Option<Instruction> {
             Instruction::Branch(kind)
         }
         0b0001_1000 => Instruction::Nop,
-        0b0001_1100 => Instruction::Hault,
+        0b0001_1001 => Instruction::Hault,
         _ => {
             // illegal Instruction
             return None;
@@ -44,11 +44,18 @@ mod tests {
     #[test]
     fn parse() {
         assert_eq!(
-            try_parse(0).unwrap(),
+            try_parse(0b0000_1000).unwrap(),
             Instruction::Load {
                 dst: Register::Acc,
                 src: MemoryMethod::Address
             }
         );
     }
+
+    #[test]
+    fn parse_halt_and_math_range() {
+        assert_eq!(try_parse(0x19), Some(Instruction::Hault));
+        assert_eq!(try_parse(0x1C), None);
+        assert_eq!(try_parse(0x40), None);
+    }
 }
@@ -0,0 +1,469 @@
+//! Policies that decide which line in a full set gets evicted
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Tracks per line usage information for a cache and picks victims from it.
+///
+/// Lines are identified by their set and their way within that set. The cache always fills
+/// invalid ways before asking for a victim, so [`ReplacementPolicy::victim`] is only called on
+/// full sets
+pub trait ReplacementPolicy {
+    /// Called when an access hits the line in `way` of `set`
+    fn touch(&mut self, set: usize, way: usize);
+
+    /// Called when a new line is brought into `way` of `set`
+    fn fill(&mut self, set: usize, way: usize);
+
+    /// Chooses the way in `set` that should be evicted next
+    fn victim(&mut self, set: usize) -> usize;
+
+    /// The policy's bookkeeping, so that it can be saved in a snapshot. Policies without any
+    /// have nothing to save
+    fn save(&self) -> Vec<u64> {
+        vec![]
+    }
+
+    /// Replaces the policy's bookkeeping with what [`ReplacementPolicy::save`] returned. Returns
+    /// false without changing anything if `state` could not have come from this policy
+    fn restore(&mut self, state: &[u64]) -> bool {
+        state.is_empty()
+    }
+}
+
+/// The built in replacement policies, used to pick one when creating a cache
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum ReplacementKind {
+    /// Evicts the least recently used line
+    #[default]
+    Lru,
+    /// Evicts the line that was filled the longest time ago
+    Fifo,
+    /// Evicts a pseudo random line, using a generator seeded with the given value so that runs
+    /// are reproducible
+    Random { seed: u64 },
+    /// Approximates lru with a binary tree of bits per set. Requires a power of two number of
+    /// ways
+    TreePlru,
+    /// Evicts the line with the fewest accesses since it was filled
+    Lfu,
+}
+
+impl ReplacementKind {
+    /// Creates the state for this policy for a cache with the given number of sets and ways
+    pub fn build(self, sets: usize, ways: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            ReplacementKind::Lru => Box::new(Lru::new(sets, ways)),
+            ReplacementKind::Fifo => Box::new(Fifo::new(sets, ways)),
+            ReplacementKind::Random { seed } => Box::new(Random::new(ways, seed)),
+            ReplacementKind::TreePlru => Box::new(TreePlru::new(sets, ways)),
+            ReplacementKind::Lfu => Box::new(Lfu::new(sets, ways)),
+        }
+    }
+}
+
+impl fmt::Display for ReplacementKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplacementKind::Lru => write!(f, "lru"),
+            ReplacementKind::Fifo => write!(f, "fifo"),
+            ReplacementKind::Random { seed } => write!(f, "random({seed})"),
+            ReplacementKind::TreePlru => write!(f, "plru"),
+            ReplacementKind::Lfu => write!(f, "lfu"),
+        }
+    }
+}
+
+/// Parses the names [`ReplacementKind`]'s `Display` impl writes, such as `lru` or `random(7)`
+impl FromStr for ReplacementKind {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match text {
+            "lru" => ReplacementKind::Lru,
+            "fifo" => ReplacementKind::Fifo,
+            "plru" => ReplacementKind::TreePlru,
+            "lfu" => ReplacementKind::Lfu,
+            _ => {
+                let seed = text
+                    .strip_prefix("random(")
+                    .and_then(|text| text.strip_suffix(')'))
+                    .ok_or_else(|| format!("unknown replacement policy `{text}`"))?;
+                let seed = seed
+                    .parse()
+                    .map_err(|_| format!("bad seed `{seed}` for random replacement"))?;
+                ReplacementKind::Random { seed }
+            }
+        })
+    }
+}
+
+/// Saves a clock followed by one value per line
+fn save_clocked(clock: u64, values: &[u64]) -> Vec<u64> {
+    [clock].iter().chain(values).copied().collect()
+}
+
+/// Restores state saved by [`save_clocked`], checking it has a value for every line
+fn restore_clocked(state: &[u64], clock: &mut u64, values: &mut [u64]) -> bool {
+    match state.split_first() {
+        Some((&saved, saved_values)) if saved_values.len() == values.len() => {
+            *clock = saved;
+            values.copy_from_slice(saved_values);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Returns the way in `set` with the smallest value in `values`, preferring lower ways on ties
+fn min_way(values: &[u64], set: usize, ways: usize) -> usize {
+    let set = &values[set * ways..(set + 1) * ways];
+    (0..ways).min_by_key(|&way| set[way]).unwrap()
+}
+
+pub struct Lru {
+    ways: usize,
+    clock: u64,
+    /// Value of `clock` when each line was last used
+    last_used: Vec<u64>,
+}
+
+impl Lru {
+    pub fn new(sets: usize, ways: usize) -> Self {
+        Self {
+            ways,
+            clock: 0,
+            last_used: vec![0; sets * ways],
+        }
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn touch(&mut self, set: usize, way: usize) {
+        self.clock += 1;
+        self.last_used[set * self.ways + way] = self.clock;
+    }
+
+    fn fill(&mut self, set: usize, way: usize) {
+        self.touch(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        min_way(&self.last_used, set, self.ways)
+    }
+
+    fn save(&self) -> Vec<u64> {
+        save_clocked(self.clock, &self.last_used)
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        restore_clocked(state, &mut self.clock, &mut self.last_used)
+    }
+}
+
+pub struct Fifo {
+    ways: usize,
+    clock: u64,
+    /// Value of `clock` when each line was filled
+    filled: Vec<u64>,
+}
+
+impl Fifo {
+    pub fn new(sets: usize, ways: usize) -> Self {
+        Self {
+            ways,
+            clock: 0,
+            filled: vec![0; sets * ways],
+        }
+    }
+}
+
+impl ReplacementPolicy for Fifo {
+    fn touch(&mut self, _set: usize, _way: usize) {}
+
+    fn fill(&mut self, set: usize, way: usize) {
+        self.clock += 1;
+        self.filled[set * self.ways + way] = self.clock;
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        min_way(&self.filled, set, self.ways)
+    }
+
+    fn save(&self) -> Vec<u64> {
+        save_clocked(self.clock, &self.filled)
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        restore_clocked(state, &mut self.clock, &mut self.filled)
+    }
+}
+
+/// Picks victims with a xorshift generator
+pub struct Random {
+    ways: usize,
+    state: u64,
+}
+
+impl Random {
+    pub fn new(ways: usize, seed: u64) -> Self {
+        Self {
+            ways,
+            // xorshift gets stuck at zero
+            state: seed.max(1),
+        }
+    }
+}
+
+impl ReplacementPolicy for Random {
+    fn touch(&mut self, _set: usize, _way: usize) {}
+
+    fn fill(&mut self, _set: usize, _way: usize) {}
+
+    fn victim(&mut self, _set: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % self.ways as u64) as usize
+    }
+
+    fn save(&self) -> Vec<u64> {
+        vec![self.state]
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        match state {
+            [state] if *state != 0 => {
+                self.state = *state;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Tree pseudo lru.
+///
+/// Each set has `ways - 1` bits arranged as a complete binary tree with the ways as leaves. Each
+/// bit points towards the half of its subtree that should be evicted next, and accessing a way
+/// flips the bits on its path to point away from it
+pub struct TreePlru {
+    ways: usize,
+    bits: Vec<bool>,
+}
+
+impl TreePlru {
+    pub fn new(sets: usize, ways: usize) -> Self {
+        assert!(
+            ways.is_power_of_two(),
+            "tree plru requires a power of two number of ways"
+        );
+        Self {
+            ways,
+            bits: vec![false; sets * (ways - 1)],
+        }
+    }
+
+    fn tree(&mut self, set: usize) -> &mut [bool] {
+        let nodes = self.ways - 1;
+        &mut self.bits[set * nodes..(set + 1) * nodes]
+    }
+}
+
+impl ReplacementPolicy for TreePlru {
+    fn touch(&mut self, set: usize, way: usize) {
+        let mut width = self.ways;
+        let tree = self.tree(set);
+        let (mut node, mut first) = (0, 0);
+        while width > 1 {
+            width /= 2;
+            // `true` points at the right half
+            let right = way >= first + width;
+            tree[node] = !right;
+            node = 2 * node + if right { 2 } else { 1 };
+            if right {
+                first += width;
+            }
+        }
+    }
+
+    fn fill(&mut self, set: usize, way: usize) {
+        self.touch(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let mut width = self.ways;
+        let tree = self.tree(set);
+        let (mut node, mut way) = (0, 0);
+        while width > 1 {
+            width /= 2;
+            if tree[node] {
+                way += width;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+        way
+    }
+
+    fn save(&self) -> Vec<u64> {
+        self.bits.iter().map(|&bit| bit as u64).collect()
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        if state.len() != self.bits.len() || state.iter().any(|&bit| bit > 1) {
+            return false;
+        }
+        self.bits = state.iter().map(|&bit| bit == 1).collect();
+        true
+    }
+}
+
+pub struct Lfu {
+    ways: usize,
+    /// Number of times each line has been used since it was filled
+    uses: Vec<u64>,
+}
+
+impl Lfu {
+    pub fn new(sets: usize, ways: usize) -> Self {
+        Self {
+            ways,
+            uses: vec![0; sets * ways],
+        }
+    }
+}
+
+impl ReplacementPolicy for Lfu {
+    fn touch(&mut self, set: usize, way: usize) {
+        self.uses[set * self.ways + way] += 1;
+    }
+
+    fn fill(&mut self, set: usize, way: usize) {
+        self.uses[set * self.ways + way] = 1;
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        min_way(&self.uses, set, self.ways)
+    }
+
+    fn save(&self) -> Vec<u64> {
+        self.uses.clone()
+    }
+
+    fn restore(&mut self, state: &[u64]) -> bool {
+        if state.len() != self.uses.len() {
+            return false;
+        }
+        self.uses.copy_from_slice(state);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fills every way of set 0 in order, then applies `touches`
+    fn filled(kind: ReplacementKind, ways: usize, touches: &[usize]) -> Box<dyn ReplacementPolicy> {
+        let mut policy = kind.build(1, ways);
+        for way in 0..ways {
+            policy.fill(0, way);
+        }
+        for &way in touches {
+            policy.touch(0, way);
+        }
+        policy
+    }
+
+    #[test]
+    fn lru() {
+        let mut policy = filled(ReplacementKind::Lru, 4, &[0, 2, 1]);
+        assert_eq!(policy.victim(0), 3);
+        policy.touch(0, 3);
+        assert_eq!(policy.victim(0), 0);
+    }
+
+    #[test]
+    fn fifo_ignores_hits() {
+        let mut policy = filled(ReplacementKind::Fifo, 4, &[0, 0, 0]);
+        assert_eq!(policy.victim(0), 0);
+        policy.fill(0, 0);
+        assert_eq!(policy.victim(0), 1);
+    }
+
+    #[test]
+    fn random_is_reproducible() {
+        let victims = |seed| {
+            let mut policy = filled(ReplacementKind::Random { seed }, 8, &[]);
+            (0..32).map(|_| policy.victim(0)).collect::<Vec<_>>()
+        };
+        assert_eq!(victims(7), victims(7));
+        assert!(victims(7).iter().all(|&way| way < 8));
+    }
+
+    #[test]
+    fn tree_plru() {
+        let mut policy = filled(ReplacementKind::TreePlru, 4, &[]);
+        // After filling 0..4 in order the tree points back at the first way
+        assert_eq!(policy.victim(0), 0);
+        policy.touch(0, 0);
+        assert_eq!(policy.victim(0), 2);
+        policy.touch(0, 2);
+        assert_eq!(policy.victim(0), 1);
+    }
+
+    #[test]
+    fn restores_saved_state() {
+        let kinds = [
+            ReplacementKind::Lru,
+            ReplacementKind::Fifo,
+            ReplacementKind::Random { seed: 3 },
+            ReplacementKind::TreePlru,
+            ReplacementKind::Lfu,
+        ];
+        for kind in kinds {
+            let mut policy = filled(kind, 4, &[2, 0, 2, 1]);
+            let mut restored = kind.build(1, 4);
+            assert!(restored.restore(&policy.save()), "{kind}");
+            let victims = |policy: &mut Box<dyn ReplacementPolicy>| {
+                (0..8)
+                    .map(|_| {
+                        let way = policy.victim(0);
+                        policy.fill(0, way);
+                        way
+                    })
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(victims(&mut restored), victims(&mut policy), "{kind}");
+        }
+        // State from a differently sized cache does not fit
+        let state = filled(ReplacementKind::Lru, 4, &[]).save();
+        assert!(!ReplacementKind::Lru.build(2, 4).restore(&state));
+    }
+
+    #[test]
+    fn lfu() {
+        let mut policy = filled(ReplacementKind::Lfu, 4, &[0, 0, 1, 3, 3, 3]);
+        assert_eq!(policy.victim(0), 2);
+        policy.fill(0, 2);
+        policy.touch(0, 2);
+        assert_eq!(policy.victim(0), 1);
+    }
+
+    #[test]
+    fn parses_display_names() {
+        for kind in [
+            ReplacementKind::Lru,
+            ReplacementKind::Fifo,
+            ReplacementKind::Random { seed: 7 },
+            ReplacementKind::TreePlru,
+            ReplacementKind::Lfu,
+        ] {
+            assert_eq!(kind.to_string().parse(), Ok(kind));
+        }
+        assert!("random(x)".parse::<ReplacementKind>().is_err());
+        assert!("mru".parse::<ReplacementKind>().is_err());
+    }
+}
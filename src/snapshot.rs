@@ -0,0 +1,400 @@
+//! Saving and restoring the complete state of a [`Computer`], so long runs can be checkpointed
+//! and bug reports can include the exact machine they happened on.
+//!
+//! A snapshot starts with [`SNAPSHOT_MAGIC`], then the format version, the payload length and a
+//! crc32 of the payload, all big endian. The payload holds the registers, counters and
+//! instruction costs, the cache hierarchy's configuration, main memory, and every cache's lines,
+//! statistics, replacement state and miss classifier
+
+use crate::{CacheConfig, Computer, ReplacementKind, WriteMissPolicy, WritePolicy, MEMORY_SIZE};
+use std::io::{self, Read, Write};
+
+/// The first bytes of every snapshot
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"C470SNAP";
+
+/// Bumped whenever the payload layout changes. Snapshots from other versions are rejected
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Writes everything needed to recreate `computer` exactly.
+///
+/// Dirty cache lines are saved as they are rather than flushed. Traces, last writers, history,
+/// watchpoints and observers are debugging aids rather than machine state, so they are left out
+pub fn write_snapshot(computer: &Computer, mut out: impl Write) -> io::Result<()> {
+    let mut payload = SnapshotWriter::default();
+    computer.save_snapshot(&mut payload);
+    let payload = payload.0;
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(&crc32(&payload).to_be_bytes())?;
+    out.write_all(&payload)
+}
+
+/// Recreates a computer saved by [`write_snapshot`], checking the header and checksum first
+pub fn read_snapshot(mut input: impl Read) -> io::Result<Computer> {
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let mut header = SnapshotReader { data: &data };
+    if !header
+        .bytes(SNAPSHOT_MAGIC.len())
+        .is_ok_and(|magic| magic == SNAPSHOT_MAGIC)
+    {
+        return Err(invalid("not a snapshot"));
+    }
+    let version = header.u16()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(format!(
+            "snapshot is version {version}, expected {SNAPSHOT_VERSION}"
+        )));
+    }
+    let len = header.u32()? as usize;
+    let checksum = header.u32()?;
+    if header.data.len() != len {
+        return Err(invalid(format!(
+            "snapshot payload is {} bytes, expected {len}",
+            header.data.len()
+        )));
+    }
+    if crc32(header.data) != checksum {
+        return Err(invalid("snapshot checksum does not match"));
+    }
+
+    let mut payload = SnapshotReader { data: header.data };
+    let computer = Computer::load_snapshot(&mut payload)?;
+    if !payload.data.is_empty() {
+        return Err(invalid("snapshot has data after the end of its payload"));
+    }
+    Ok(computer)
+}
+
+/// Whether `data` starts like a snapshot rather than a memory image
+pub fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(SNAPSHOT_MAGIC)
+}
+
+pub(crate) fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Builds a snapshot's payload, with every number big endian
+#[derive(Default)]
+pub(crate) struct SnapshotWriter(Vec<u8>);
+
+impl SnapshotWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.0.extend(value.to_be_bytes());
+    }
+
+    /// Sizes and counts are saved as 64 bits so snapshots don't depend on the host
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend(bytes);
+    }
+
+    pub fn cache_config(&mut self, config: &CacheConfig) {
+        self.usize(config.line_size);
+        self.usize(config.sets);
+        self.usize(config.ways);
+        match config.replacement {
+            ReplacementKind::Lru => self.u8(0),
+            ReplacementKind::Fifo => self.u8(1),
+            ReplacementKind::Random { seed } => {
+                self.u8(2);
+                self.u64(seed);
+            }
+            ReplacementKind::TreePlru => self.u8(3),
+            ReplacementKind::Lfu => self.u8(4),
+        }
+        self.bool(config.write_policy == WritePolicy::WriteThrough);
+        self.bool(config.write_miss == WriteMissPolicy::NoWriteAllocate);
+        self.u64(config.latency);
+    }
+}
+
+/// Reads back what a [`SnapshotWriter`] wrote, failing instead of panicking on bad data
+pub(crate) struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(invalid("snapshot ends early"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid(format!("bad flag {value} in snapshot"))),
+        }
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    /// Reads a size, count or index, rejecting anything above `max`, the most the part of the
+    /// machine holding it can have
+    pub fn usize(&mut self, max: usize) -> io::Result<usize> {
+        match self.u64()? {
+            value if value <= max as u64 => Ok(value as usize),
+            value => Err(invalid(format!(
+                "{value} in snapshot is more than the {max} it can be"
+            ))),
+        }
+    }
+
+    /// How many bytes are left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Reads a cache config, checking it describes a cache that can be built
+    pub fn cache_config(&mut self) -> io::Result<CacheConfig> {
+        // No cache can hold more than memory
+        let line_size = self.usize(MEMORY_SIZE)?;
+        let sets = self.usize(MEMORY_SIZE)?;
+        let ways = self.usize(MEMORY_SIZE)?;
+        let replacement = match self.u8()? {
+            0 => ReplacementKind::Lru,
+            1 => ReplacementKind::Fifo,
+            2 => ReplacementKind::Random { seed: self.u64()? },
+            3 => ReplacementKind::TreePlru,
+            4 => ReplacementKind::Lfu,
+            kind => return Err(invalid(format!("unknown replacement policy {kind}"))),
+        };
+        let write_policy = match self.bool()? {
+            true => WritePolicy::WriteThrough,
+            false => WritePolicy::WriteBack,
+        };
+        let write_miss = match self.bool()? {
+            true => WriteMissPolicy::NoWriteAllocate,
+            false => WriteMissPolicy::WriteAllocate,
+        };
+        let config = CacheConfig::new(line_size, sets, ways)
+            .with_replacement(replacement)
+            .with_write_policy(write_policy, write_miss)
+            .with_latency(self.u64()?);
+
+        let size = line_size
+            .checked_mul(sets)
+            .and_then(|size| size.checked_mul(ways));
+        let plru_ways = replacement != ReplacementKind::TreePlru || ways.is_power_of_two();
+        if !line_size.is_power_of_two()
+            || !sets.is_power_of_two()
+            || ways == 0
+            || !plru_ways
+            || !size.is_some_and(|size| size <= MEMORY_SIZE)
+        {
+            return Err(invalid(format!(
+                "snapshot has an impossible cache: {line_size} byte lines, {sets} sets, \
+                 {ways} ways, {replacement} replacement"
+            )));
+        }
+        Ok(config)
+    }
+}
+
+/// The crc32 used by zip and png
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    /// Fills 0x2000 onwards with a countdown from 40
+    fn program() -> [u8; MEMORY_SIZE] {
+        assemble(
+            "
+                LOAD ACC, #40
+                LOAD MAR, #0x2000
+            loop:
+                STORE ACC, [MAR]
+                INC MAR
+                DEC ACC
+                BNE loop
+                HALT
+            ",
+        )
+        .unwrap()
+    }
+
+    /// Runs [`program`] through split caches in front of an L2, using random replacement so the
+    /// generator's state matters
+    fn computer() -> Computer {
+        let small = CacheConfig::new(4, 2, 2).with_replacement(ReplacementKind::Random { seed: 9 });
+        let config = HierarchyConfig {
+            l1: L1Config::Split {
+                instruction: small,
+                data: small.with_latency(2),
+            },
+            lower: vec![CacheConfig::new(4, 4, 4).with_replacement(ReplacementKind::TreePlru)],
+            inclusion: Inclusion::Exclusive,
+            memory_latency: 30,
+        };
+        let mut computer =
+            Computer::with_memory_system(MemorySystem::with_config(program(), config));
+        computer.set_instruction_costs(InstructionCosts {
+            store: 3,
+            ..InstructionCosts::default()
+        });
+        computer
+    }
+
+    fn snapshot(computer: &Computer) -> Vec<u8> {
+        let mut data = vec![];
+        write_snapshot(computer, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn resumes_exactly() {
+        let mut original = computer();
+        original.run_for(50).unwrap();
+        let data = snapshot(&original);
+        assert!(is_snapshot(&data));
+        let mut restored = read_snapshot(&data[..]).unwrap();
+        assert_eq!(restored.state(), original.state());
+        assert_eq!(
+            restored.memory_system().config(),
+            original.memory_system().config()
+        );
+        // Dirty lines are restored without being flushed
+        assert_eq!(restored.memory(), original.memory());
+        assert_eq!(snapshot(&restored), data);
+
+        assert_eq!(original.run(), Ok(StopReason::Halted));
+        assert_eq!(restored.run(), Ok(StopReason::Halted));
+        assert_eq!(restored.memory(), original.memory());
+        assert_eq!(restored.timing(), original.timing());
+        assert_eq!(
+            restored.memory_system().report(),
+            original.memory_system().report()
+        );
+        assert!(restored.halted());
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let data = snapshot(&computer());
+        let error = |data: &[u8]| read_snapshot(data).err().unwrap().to_string();
+
+        assert_eq!(error(b"C470SNA"), "not a snapshot");
+        let mut damaged = data.clone();
+        damaged[9] = 2;
+        assert_eq!(error(&damaged), "snapshot is version 2, expected 1");
+        assert_eq!(
+            error(&data[..100]),
+            format!("snapshot payload is 82 bytes, expected {}", data.len() - 18)
+        );
+        let mut damaged = data.clone();
+        damaged[100] ^= 1;
+        assert_eq!(error(&damaged), "snapshot checksum does not match");
+
+        // A checksum only catches accidents, so the payload is still checked
+        let mut payload = SnapshotWriter::default();
+        computer().save_snapshot(&mut payload);
+        let mut payload = payload.0;
+        // Registers, counters and costs take 76 bytes, then comes the L1 kind and its config
+        payload[77..85].copy_from_slice(&3u64.to_be_bytes());
+        let mut damaged = data[..10].to_vec();
+        damaged.extend((payload.len() as u32).to_be_bytes());
+        damaged.extend(crc32(&payload).to_be_bytes());
+        damaged.extend(payload);
+        assert_eq!(
+            error(&damaged),
+            "snapshot has an impossible cache: 3 byte lines, 2 sets, 2 ways, random(9) replacement"
+        );
+    }
+
+    #[test]
+    fn resumes_the_largest_caches() {
+        for replacement in [
+            ReplacementKind::Lru,
+            ReplacementKind::Fifo,
+            ReplacementKind::Random { seed: 9 },
+            ReplacementKind::TreePlru,
+            ReplacementKind::Lfu,
+        ] {
+            // One byte lines in a direct mapped cache covering all of memory
+            let config = CacheConfig::new(1, MEMORY_SIZE, 1).with_replacement(replacement);
+            let mut original = Computer::with_cache(program(), config);
+            original.run_for(50).unwrap();
+            let data = snapshot(&original);
+            let mut restored = read_snapshot(&data[..]).unwrap();
+            assert_eq!(snapshot(&restored), data);
+            assert_eq!(original.run(), Ok(StopReason::Halted));
+            assert_eq!(restored.run(), Ok(StopReason::Halted));
+            assert_eq!(
+                restored.memory_system().report(),
+                original.memory_system().report()
+            );
+        }
+    }
+
+    #[test]
+    fn resumes_past_the_end_of_memory() {
+        // BRA 0xFFFF, then a NOP in the last byte of memory
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0xFF, 0xFF]);
+        memory[0xFFFF] = 0x18;
+        let mut computer = Computer::new(memory);
+        computer.run_for(2).unwrap();
+        let mut restored = read_snapshot(&snapshot(&computer)[..]).unwrap();
+        assert_eq!(
+            restored.step(),
+            Err(EmulatorError::PcOverflow { pc: 0xFFFF })
+        );
+    }
+
+    #[test]
+    fn checksums_like_zip() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,348 @@
+//! Runs a program under a grid of cache configurations and tabulates how each one performed
+
+use crate::{
+    CacheConfig, CacheStats, Computer, EmulatorError, HierarchyConfig, L1Config, MemorySystem,
+    ReplacementKind, StopReason, TimingReport, MEMORY_SIZE,
+};
+use std::io::{self, Write};
+
+/// Every combination of these parameters is tried, skipping ones that do not make a valid cache
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SweepGrid {
+    /// Total cache sizes in bytes
+    pub sizes: Vec<usize>,
+    pub line_sizes: Vec<usize>,
+    pub ways: Vec<usize>,
+    pub replacements: Vec<ReplacementKind>,
+    /// The hierarchy each configuration is tried in
+    pub hierarchy: HierarchyConfig,
+    /// The level of `hierarchy` each configuration replaces, 1 for the L1, which becomes a
+    /// unified cache, 2 for the level below it and so on
+    pub level: usize,
+}
+
+impl SweepGrid {
+    /// The valid configurations in the grid, ordered by size, then line size, then ways, then
+    /// replacement policy. Configurations whose line size differs from the other levels in
+    /// `hierarchy` are skipped, as is everything if `hierarchy` has no `level`
+    pub fn configs(&self) -> Vec<CacheConfig> {
+        let mut configs = vec![];
+        for &size in &self.sizes {
+            for &line_size in &self.line_sizes {
+                for &ways in &self.ways {
+                    let line_bytes = line_size * ways;
+                    if !line_size.is_power_of_two()
+                        || ways == 0
+                        || size % line_bytes != 0
+                        || !(size / line_bytes).is_power_of_two()
+                        || size > MEMORY_SIZE
+                    {
+                        continue;
+                    }
+                    for &replacement in &self.replacements {
+                        if replacement == ReplacementKind::TreePlru && !ways.is_power_of_two() {
+                            continue;
+                        }
+                        let config = CacheConfig::new(line_size, size / line_bytes, ways)
+                            .with_replacement(replacement);
+                        if self
+                            .hierarchy(config)
+                            .is_some_and(|hierarchy| hierarchy.check().is_ok())
+                        {
+                            configs.push(config);
+                        }
+                    }
+                }
+            }
+        }
+        configs
+    }
+
+    /// `hierarchy` with `config` in place of `level`, or `None` if there is no such level
+    pub fn hierarchy(&self, config: CacheConfig) -> Option<HierarchyConfig> {
+        let mut hierarchy = self.hierarchy.clone();
+        match self.level {
+            0 => return None,
+            1 => hierarchy.l1 = L1Config::Unified(config),
+            level => *hierarchy.lower.get_mut(level - 2)? = config,
+        }
+        Some(hierarchy)
+    }
+}
+
+impl Default for SweepGrid {
+    /// 128 byte to 2 KiB caches with 4 to 32 byte lines, direct mapped to 8 way, under every
+    /// replacement policy, as the only cache in front of main memory
+    fn default() -> Self {
+        Self {
+            sizes: vec![128, 256, 512, 1024, 2048],
+            line_sizes: vec![4, 8, 16, 32],
+            ways: vec![1, 2, 4, 8],
+            replacements: vec![
+                ReplacementKind::Lru,
+                ReplacementKind::Fifo,
+                ReplacementKind::Random { seed: 1 },
+                ReplacementKind::TreePlru,
+                ReplacementKind::Lfu,
+            ],
+            hierarchy: HierarchyConfig::default(),
+            level: 1,
+        }
+    }
+}
+
+/// How many instructions each configuration gets by default before its run is cut short
+pub const DEFAULT_SWEEP_INSTRUCTION_LIMIT: u64 = 1_000_000;
+
+/// How a program performed with a single cache configuration
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SweepResult {
+    pub config: CacheConfig,
+    /// Why the run stopped. Statistics are for everything executed up to that point
+    pub outcome: Result<StopReason, EmulatorError>,
+    pub stats: CacheStats,
+    pub timing: TimingReport,
+}
+
+/// Runs the program in `memory` once for every configuration in `grid`, placing each one in the
+/// grid's hierarchy. The statistics are for the swept level, while the timing covers the whole
+/// hierarchy. Each run executes at most `instruction_limit` instructions, and a run that faults
+/// or hits the limit is still reported
+pub fn sweep(
+    memory: &[u8; MEMORY_SIZE],
+    grid: &SweepGrid,
+    instruction_limit: u64,
+) -> Vec<SweepResult> {
+    let name = format!("L{}", grid.level);
+    grid.configs()
+        .into_iter()
+        .map(|config| {
+            let hierarchy = grid.hierarchy(config).unwrap();
+            let mut computer =
+                Computer::with_memory_system(MemorySystem::with_config(*memory, hierarchy));
+            let outcome = computer.run_for(instruction_limit);
+            computer.flush();
+            let report = computer.memory_system().report();
+            let level = report.levels.iter().find(|level| level.name == name);
+            SweepResult {
+                config,
+                outcome,
+                stats: level.unwrap().stats,
+                timing: computer.timing(),
+            }
+        })
+        .collect()
+}
+
+const COLUMNS: [&str; 15] = [
+    "size",
+    "line_size",
+    "sets",
+    "ways",
+    "replacement",
+    "accesses",
+    "hits",
+    "misses",
+    "hit_rate",
+    "compulsory",
+    "capacity",
+    "conflict",
+    "cycles",
+    "cpi",
+    "outcome",
+];
+
+impl SweepResult {
+    /// Values for each of [`COLUMNS`], and whether each one is a string
+    fn values(&self) -> [(String, bool); COLUMNS.len()] {
+        let config = &self.config;
+        let stats = &self.stats;
+        [
+            (config.size().to_string(), false),
+            (config.line_size.to_string(), false),
+            (config.sets.to_string(), false),
+            (config.ways.to_string(), false),
+            (config.replacement.to_string(), true),
+            (stats.accesses().to_string(), false),
+            (stats.hits.to_string(), false),
+            (stats.misses.to_string(), false),
+            (format!("{:.4}", stats.hit_rate()), false),
+            (stats.miss_kinds.compulsory.to_string(), false),
+            (stats.miss_kinds.capacity.to_string(), false),
+            (stats.miss_kinds.conflict.to_string(), false),
+            (self.timing.cycles().to_string(), false),
+            (format!("{:.4}", self.timing.cpi()), false),
+            (self.outcome_text(), true),
+        ]
+    }
+
+    fn outcome_text(&self) -> String {
+        match self.outcome {
+            Ok(StopReason::Halted) => "halted".to_owned(),
+            Ok(StopReason::InstructionLimit) => "instruction limit".to_owned(),
+            Ok(reason) => format!("{reason:?}"),
+            Err(error) => error.to_string(),
+        }
+    }
+}
+
+/// Writes one row per result, after a header row naming the columns
+pub fn write_sweep_csv(results: &[SweepResult], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "{}", COLUMNS.join(","))?;
+    for result in results {
+        let values: Vec<_> = result
+            .values()
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect();
+        writeln!(out, "{}", values.join(","))?;
+    }
+    Ok(())
+}
+
+/// Writes an array with one object per result
+pub fn write_sweep_json(results: &[SweepResult], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, result) in results.iter().enumerate() {
+        let fields: Vec<_> = COLUMNS
+            .iter()
+            .zip(result.values())
+            .map(|(name, (value, string))| match string {
+                true => format!("\"{name}\": \"{value}\""),
+                false => format!("\"{name}\": {value}"),
+            })
+            .collect();
+        let separator = if i + 1 == results.len() { "" } else { "," };
+        writeln!(out, "  {{{}}}{separator}", fields.join(", "))?;
+    }
+    writeln!(out, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_invalid_configs() {
+        let grid = SweepGrid {
+            sizes: vec![64, 96],
+            line_sizes: vec![16],
+            ways: vec![1, 2, 3],
+            replacements: vec![ReplacementKind::Lru, ReplacementKind::TreePlru],
+            ..SweepGrid::default()
+        };
+        // 96 bytes only works as 2 sets of 3 ways, which plru cannot handle
+        let configs: Vec<_> = grid
+            .configs()
+            .iter()
+            .map(|config| (config.size(), config.sets, config.ways, config.replacement))
+            .collect();
+        assert_eq!(
+            configs,
+            vec![
+                (64, 4, 1, ReplacementKind::Lru),
+                (64, 4, 1, ReplacementKind::TreePlru),
+                (64, 2, 2, ReplacementKind::Lru),
+                (64, 2, 2, ReplacementKind::TreePlru),
+                (96, 2, 3, ReplacementKind::Lru),
+            ]
+        );
+    }
+
+    #[test]
+    fn sweeps_lower_levels() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LOAD ACC #5, HALT
+        memory[..3].copy_from_slice(&[0x09, 0x05, 0x19]);
+        let mut grid = SweepGrid {
+            sizes: vec![256],
+            line_sizes: vec![8, 16],
+            ways: vec![4],
+            replacements: vec![ReplacementKind::Lru],
+            hierarchy: HierarchyConfig {
+                lower: vec![CacheConfig::new(16, 16, 1)],
+                ..HierarchyConfig::default()
+            },
+            level: 2,
+        };
+        // Only 16 byte lines match the L1
+        let results = sweep(&memory, &grid, DEFAULT_SWEEP_INSTRUCTION_LIMIT);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].config, CacheConfig::new(16, 4, 4));
+        // The L1 misses once, so the L2 sees a single access
+        assert_eq!(results[0].stats.accesses(), 1);
+
+        grid.level = 3;
+        assert!(grid.configs().is_empty());
+    }
+
+    #[test]
+    fn writes_tables() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LOAD ACC #5, HALT
+        memory[..3].copy_from_slice(&[0x09, 0x05, 0x19]);
+        let grid = SweepGrid {
+            sizes: vec![32],
+            line_sizes: vec![16],
+            ways: vec![1, 2],
+            replacements: vec![ReplacementKind::Fifo],
+            ..SweepGrid::default()
+        };
+        let results = sweep(&memory, &grid, DEFAULT_SWEEP_INSTRUCTION_LIMIT);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].stats.hits, 2);
+        assert_eq!(results[0].stats.misses, 1);
+
+        let mut csv = vec![];
+        write_sweep_csv(&results, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("size,line_size,sets,ways,replacement,accesses,hits,misses,hit_rate,compulsory,capacity,conflict,cycles,cpi,outcome")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("32,16,2,1,fifo,3,2,1,0.6667,1,0,0,105,52.5000,halted")
+        );
+
+        let mut json = vec![];
+        write_sweep_json(&results, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[\n  {\"size\": 32, \"line_size\": 16, \"sets\": 2, \"ways\": 1, \"replacement\": \"fifo\","));
+        assert!(json.ends_with("}\n]\n"));
+        assert_eq!(json.matches('{').count(), 2);
+        assert!(json.contains("\"outcome\": \"halted\"}"));
+    }
+
+    #[test]
+    fn reports_runs_that_do_not_halt() {
+        let grid = SweepGrid {
+            sizes: vec![32],
+            line_sizes: vec![16],
+            ways: vec![1],
+            replacements: vec![ReplacementKind::Lru],
+            ..SweepGrid::default()
+        };
+        // BRA 0x0000
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..3].copy_from_slice(&[0x10, 0x00, 0x00]);
+        let results = sweep(&memory, &grid, 100);
+        assert_eq!(results[0].outcome, Ok(StopReason::InstructionLimit));
+        assert_eq!(results[0].timing.instructions, 100);
+
+        // NOP, then an illegal opcode
+        memory[..2].copy_from_slice(&[0x18, 0x1A]);
+        let results = sweep(&memory, &grid, DEFAULT_SWEEP_INSTRUCTION_LIMIT);
+        let error = EmulatorError::IllegalOpcode {
+            opcode: 0x1A,
+            pc: 0x0001,
+        };
+        assert_eq!(results[0].outcome, Err(error));
+        assert_eq!(results[0].timing.instructions, 1);
+        let mut csv = vec![];
+        write_sweep_csv(&results, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.ends_with(",illegal opcode 0b00011010 at 0x0001\n"));
+    }
+}
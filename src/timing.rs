@@ -0,0 +1,111 @@
+//! Cycle accounting for the cpu
+
+use crate::Instruction;
+use std::fmt;
+
+/// Cycles each kind of instruction takes on top of the memory accesses it makes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InstructionCosts {
+    pub math: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch: u64,
+    pub nop: u64,
+    pub hault: u64,
+}
+
+impl InstructionCosts {
+    pub fn cost(&self, ins: &Instruction) -> u64 {
+        match ins {
+            Instruction::Mathmatical { .. } => self.math,
+            Instruction::Load { .. } => self.load,
+            Instruction::Store { .. } => self.store,
+            Instruction::Branch(_) => self.branch,
+            Instruction::Nop => self.nop,
+            Instruction::Hault => self.hault,
+        }
+    }
+}
+
+impl Default for InstructionCosts {
+    /// Every instruction takes one cycle
+    fn default() -> Self {
+        Self {
+            math: 1,
+            load: 1,
+            store: 1,
+            branch: 1,
+            nop: 1,
+            hault: 1,
+        }
+    }
+}
+
+/// How long a program took to run
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct TimingReport {
+    pub instructions: u64,
+    /// Cycles charged for executing instructions, not counting memory
+    pub execute_cycles: u64,
+    /// Cycles spent waiting on memory accesses
+    pub memory_cycles: u64,
+}
+
+impl TimingReport {
+    pub fn cycles(&self) -> u64 {
+        self.execute_cycles + self.memory_cycles
+    }
+
+    /// Average cycles per instruction, or 0 if nothing was executed
+    pub fn cpi(&self) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            n => self.cycles() as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions in {} cycles ({} executing, {} on memory), CPI {:.2}",
+            self.instructions,
+            self.cycles(),
+            self.execute_cycles,
+            self.memory_cycles,
+            self.cpi()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn charges_instructions_and_memory() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LOAD ACC #5, HALT
+        memory[..3].copy_from_slice(&[0x09, 0x05, 0x19]);
+        let mut computer = Computer::new(memory);
+        computer.set_instruction_costs(InstructionCosts {
+            load: 3,
+            ..InstructionCosts::default()
+        });
+        computer.run().unwrap();
+
+        // Three uncached reads from 100 cycle memory
+        let timing = computer.timing();
+        assert_eq!(
+            timing,
+            TimingReport {
+                instructions: 2,
+                execute_cycles: 4,
+                memory_cycles: 300,
+            }
+        );
+        assert_eq!(timing.cpi(), 152.0);
+        assert_eq!(computer.cycles(), 304);
+    }
+}
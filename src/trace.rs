@@ -0,0 +1,251 @@
+//! Recording memory accesses made by the cpu, and replaying them through a cache hierarchy
+
+use crate::{AccessSource, HierarchyConfig, MemorySystem, MEMORY_SIZE};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// First bytes of a binary trace file
+const MAGIC: &[u8; 4] = b"RTRC";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single read or write made by the cpu
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TraceEntry {
+    pub addr: u16,
+    /// Number of bytes accessed, either 1 or 2
+    pub size: u8,
+    pub kind: AccessKind,
+    pub source: AccessSource,
+}
+
+impl TraceEntry {
+    /// Packs everything but the address into a byte: bit 0 is set for writes, bit 1 for
+    /// instruction fetches and bit 2 for 16 bit accesses
+    fn flags(&self) -> u8 {
+        (self.kind == AccessKind::Write) as u8
+            | ((self.source == AccessSource::Instruction) as u8) << 1
+            | ((self.size == 2) as u8) << 2
+    }
+
+    fn from_flags(addr: u16, flags: u8) -> io::Result<Self> {
+        if flags & !0b111 != 0 {
+            return Err(invalid(format!("unknown trace flags 0b{flags:08b}")));
+        }
+        Ok(Self {
+            addr,
+            size: if flags & 0b100 != 0 { 2 } else { 1 },
+            kind: if flags & 0b001 != 0 {
+                AccessKind::Write
+            } else {
+                AccessKind::Read
+            },
+            source: if flags & 0b010 != 0 {
+                AccessSource::Instruction
+            } else {
+                AccessSource::Data
+            },
+        })
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Writes a trace as the magic bytes followed by three bytes per entry: the big endian address
+/// and a flags byte
+pub fn write_trace_binary(trace: &[TraceEntry], mut out: impl Write) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(MAGIC.len() + trace.len() * 3);
+    bytes.extend_from_slice(MAGIC);
+    for entry in trace {
+        bytes.extend_from_slice(&entry.addr.to_be_bytes());
+        bytes.push(entry.flags());
+    }
+    out.write_all(&bytes)
+}
+
+/// Writes a trace with one entry per line, formatted as `<I|D> <R|W> <size> <hex address>`
+pub fn write_trace_text(trace: &[TraceEntry], mut out: impl Write) -> io::Result<()> {
+    for entry in trace {
+        let source = match entry.source {
+            AccessSource::Instruction => 'I',
+            AccessSource::Data => 'D',
+        };
+        let kind = match entry.kind {
+            AccessKind::Read => 'R',
+            AccessKind::Write => 'W',
+        };
+        writeln!(out, "{source} {kind} {} {:04X}", entry.size, entry.addr)?;
+    }
+    Ok(())
+}
+
+/// Reads a trace written by either [`write_trace_binary`] or [`write_trace_text`]
+pub fn read_trace(input: impl Read) -> io::Result<Vec<TraceEntry>> {
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.starts_with(MAGIC) {
+        let mut bytes = vec![];
+        input.read_to_end(&mut bytes)?;
+        let entries = &bytes[MAGIC.len()..];
+        if entries.len() % 3 != 0 {
+            return Err(invalid(
+                "binary trace ends partway through an entry".to_owned(),
+            ));
+        }
+        entries
+            .chunks(3)
+            .map(|entry| TraceEntry::from_flags(u16::from_be_bytes([entry[0], entry[1]]), entry[2]))
+            .collect()
+    } else {
+        let mut trace = vec![];
+        for (number, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = parse_line(&line)
+                .ok_or_else(|| invalid(format!("line {}: bad trace entry {line:?}", number + 1)))?;
+            trace.push(entry);
+        }
+        Ok(trace)
+    }
+}
+
+fn parse_line(line: &str) -> Option<TraceEntry> {
+    let mut parts = line.split_whitespace();
+    let source = match parts.next()? {
+        "I" => AccessSource::Instruction,
+        "D" => AccessSource::Data,
+        _ => return None,
+    };
+    let kind = match parts.next()? {
+        "R" => AccessKind::Read,
+        "W" => AccessKind::Write,
+        _ => return None,
+    };
+    let size = match parts.next()? {
+        "1" => 1,
+        "2" => 2,
+        _ => return None,
+    };
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(TraceEntry {
+        addr,
+        size,
+        kind,
+        source,
+    })
+}
+
+/// Runs every access in `trace` through a fresh cache hierarchy, without executing anything.
+///
+/// Memory starts out zeroed and writes store zeros, since only the cache behaviour is of
+/// interest
+pub fn replay_trace(trace: &[TraceEntry], config: HierarchyConfig) -> MemorySystem {
+    let mut system = MemorySystem::with_config([0; MEMORY_SIZE], config);
+    for entry in trace {
+        for i in 0..entry.size as u16 {
+            let addr = entry.addr.wrapping_add(i);
+            match entry.kind {
+                AccessKind::Read => {
+                    system.read(addr, entry.source);
+                }
+                AccessKind::Write => system.write(addr, 0),
+            }
+        }
+    }
+    system.flush();
+    system
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Computer;
+
+    fn trace() -> Vec<TraceEntry> {
+        vec![
+            TraceEntry {
+                addr: 0x0000,
+                size: 1,
+                kind: AccessKind::Read,
+                source: AccessSource::Instruction,
+            },
+            TraceEntry {
+                addr: 0x0001,
+                size: 2,
+                kind: AccessKind::Read,
+                source: AccessSource::Instruction,
+            },
+            TraceEntry {
+                addr: 0x2000,
+                size: 1,
+                kind: AccessKind::Write,
+                source: AccessSource::Data,
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut bytes = vec![];
+        write_trace_binary(&trace(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 4 + 3 * 3);
+        assert_eq!(read_trace(&bytes[..]).unwrap(), trace());
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut text = vec![];
+        write_trace_text(&trace(), &mut text).unwrap();
+        assert_eq!(
+            String::from_utf8(text.clone()).unwrap(),
+            "I R 1 0000\nI R 2 0001\nD W 1 2000\n"
+        );
+        assert_eq!(read_trace(&text[..]).unwrap(), trace());
+    }
+
+    #[test]
+    fn rejects_bad_text() {
+        let err = read_trace(&b"I R 1 0000\nX R 1 0000\n"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2"));
+    }
+
+    #[test]
+    fn replay_counts_every_byte() {
+        let system = replay_trace(&trace(), HierarchyConfig::default());
+        let report = system.report();
+        assert_eq!(report.accesses, 4);
+        assert_eq!(report.levels[0].stats.misses, 2);
+    }
+
+    #[test]
+    fn replay_matches_execution() {
+        let mut memory = [0; MEMORY_SIZE];
+        // LOAD MAR #0x1000, LOAD ACC [MAR], STORE ACC [0x2000], HALT
+        memory[..10].copy_from_slice(&[0x0D, 0x10, 0x00, 0x0A, 0x00, 0x20, 0x00, 0x19, 0, 0]);
+        let mut computer = Computer::with_memory_system(MemorySystem::with_config(
+            memory,
+            HierarchyConfig::default(),
+        ));
+        computer.record_trace();
+        computer.run().unwrap();
+
+        let trace = computer.trace().unwrap();
+        assert_eq!(trace.len(), 8);
+        let replayed = replay_trace(trace, HierarchyConfig::default());
+        assert_eq!(
+            replayed.report().to_string(),
+            computer.memory_system().report().to_string()
+        );
+        assert_eq!(replayed.cycles(), computer.memory_system().cycles());
+    }
+}
@@ -0,0 +1,241 @@
+//! Watchpoints that stop the cpu when an instruction reads or writes data in a range of memory
+
+use crate::{disassemble_one, AccessKind, Instruction, MAX_INSTRUCTION_SIZE};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Which accesses a watchpoint fires on
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes
+    Access,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    /// Only fire when the value read or written is this. For 16 bit accesses this is compared
+    /// against the whole big endian value
+    pub value: Option<u16>,
+}
+
+impl Watchpoint {
+    /// Fires on every `kind` access to any byte in `range`
+    pub fn new(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Self {
+            range,
+            kind,
+            value: None,
+        }
+    }
+
+    /// Only fires when `value` is read or written
+    pub fn when_value(self, value: u16) -> Self {
+        Self {
+            value: Some(value),
+            ..self
+        }
+    }
+
+    /// Whether an access of `size` bytes starting at `addr`, reading or writing `value`, should
+    /// fire this watchpoint
+    pub fn matches(&self, addr: u16, size: u8, kind: AccessKind, value: u16) -> bool {
+        let last = addr.saturating_add(size as u16 - 1);
+        let overlaps = addr <= *self.range.end() && last >= *self.range.start();
+        let kind_matches = matches!(
+            (self.kind, kind),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, AccessKind::Read)
+                | (WatchKind::Write, AccessKind::Write)
+        );
+        overlaps && kind_matches && (self.value.is_none() || self.value == Some(value))
+    }
+}
+
+/// Parses `<addr>[-<last addr>][:r|w|rw][=<value>]`, where numbers are hex with `0x` or
+/// decimal. Watchpoints fire on writes unless told otherwise, so `0x2000-0x20FF=0` watches for
+/// zeros being written anywhere in 0x2000 to 0x20FF
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let number = |text: &str| {
+            let parsed = match text.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => text.parse(),
+            };
+            parsed.map_err(|_| format!("bad number `{text}` in watchpoint"))
+        };
+        let (text, value) = match text.split_once('=') {
+            Some((text, value)) => (text, Some(number(value)?)),
+            None => (text, None),
+        };
+        let (range, kind) = match text.split_once(':') {
+            Some((range, "r")) => (range, WatchKind::Read),
+            Some((range, "w")) => (range, WatchKind::Write),
+            Some((range, "rw")) => (range, WatchKind::Access),
+            Some((_, kind)) => return Err(format!("unknown watchpoint kind `{kind}`")),
+            None => (text, WatchKind::Write),
+        };
+        let range = match range.split_once('-') {
+            Some((start, last)) => number(start)?..=number(last)?,
+            None => number(range)?..=number(range)?,
+        };
+        if range.is_empty() {
+            return Err("watchpoint range ends before it starts".to_owned());
+        }
+        Ok(Watchpoint { range, kind, value })
+    }
+}
+
+/// An access that fired a watchpoint, stopping the cpu after the instruction making it finished
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WatchHit {
+    /// Index of the watchpoint, see [`crate::Computer::watchpoints`]
+    pub watchpoint: usize,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// The opcode followed by the operand bytes the instruction used, zero padded
+    pub bytes: [u8; MAX_INSTRUCTION_SIZE],
+    pub addr: u16,
+    pub size: u8,
+    pub kind: AccessKind,
+    /// The value in memory before the access
+    pub old: u16,
+    /// The value in memory after the access. The same as `old` for reads
+    pub new: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never empty, so there is always a line
+        let text = disassemble_one(&self.bytes, self.pc).unwrap().text;
+        write!(
+            f,
+            "watchpoint {}: {text} at 0x{:04X} ",
+            self.watchpoint, self.pc
+        )?;
+        match self.kind {
+            AccessKind::Read => write!(
+                f,
+                "read {} bits: 0x{:X} from [0x{:04X}]",
+                self.size * 8,
+                self.new,
+                self.addr
+            ),
+            AccessKind::Write => write!(
+                f,
+                "wrote {} bits: 0x{:X} -> 0x{:X} to [0x{:04X}]",
+                self.size * 8,
+                self.old,
+                self.new,
+                self.addr
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    /// Counts down from 3, storing each value to 0x2000 in turn
+    fn countdown() -> Computer {
+        let program = assemble(
+            "
+                LOAD ACC, #3
+            loop:
+                STORE ACC, [0x2000]
+                DEC ACC
+                BNE loop
+                STORE ACC, [0x2001]
+                LOAD ACC, [0x2000]
+                HALT
+            ",
+        )
+        .unwrap();
+        Computer::with_cache(program, CacheConfig::default())
+    }
+
+    #[test]
+    fn matches_accesses() {
+        let watchpoint = Watchpoint::new(0x2000..=0x2003, WatchKind::Write);
+        assert!(watchpoint.matches(0x2003, 1, AccessKind::Write, 5));
+        assert!(watchpoint.matches(0x1FFF, 2, AccessKind::Write, 5));
+        assert!(!watchpoint.matches(0x1FFE, 2, AccessKind::Write, 5));
+        assert!(!watchpoint.matches(0x2000, 1, AccessKind::Read, 5));
+        assert!(!watchpoint.matches(0xFFFF, 1, AccessKind::Write, 5));
+
+        let watchpoint = Watchpoint::new(0x2000..=0x2000, WatchKind::Access).when_value(0);
+        assert!(watchpoint.matches(0x2000, 1, AccessKind::Read, 0));
+        assert!(!watchpoint.matches(0x2000, 1, AccessKind::Write, 1));
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        assert_eq!(
+            "0x2000-0x20FF=0".parse(),
+            Ok(Watchpoint::new(0x2000..=0x20FF, WatchKind::Write).when_value(0))
+        );
+        assert_eq!(
+            "8192:rw".parse(),
+            Ok(Watchpoint::new(0x2000..=0x2000, WatchKind::Access))
+        );
+        assert_eq!(
+            "0x2000:x".parse::<Watchpoint>(),
+            Err("unknown watchpoint kind `x`".to_owned())
+        );
+        assert!("0x2001-0x2000".parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn stops_on_writes() {
+        let mut computer = countdown();
+        computer.add_watchpoint(Watchpoint::new(0x2000..=0x2001, WatchKind::Write));
+        let mut values = vec![];
+        while computer.run() == Ok(StopReason::Watchpoint) {
+            let hit = computer.watch_hit().unwrap();
+            values.push((hit.addr, hit.old, hit.new));
+        }
+        assert_eq!(
+            values,
+            [
+                (0x2000, 0, 3),
+                (0x2000, 3, 2),
+                (0x2000, 2, 1),
+                (0x2001, 0, 0)
+            ]
+        );
+        assert!(computer.halted());
+    }
+
+    #[test]
+    fn stops_on_conditions() {
+        let mut computer = countdown();
+        computer.add_watchpoint(Watchpoint::new(0x2000..=0x2000, WatchKind::Write).when_value(1));
+        computer.add_watchpoint(Watchpoint::new(0x2000..=0x2000, WatchKind::Read));
+        assert_eq!(computer.run(), Ok(StopReason::Watchpoint));
+        let hit = computer.watch_hit().unwrap().clone();
+        assert_eq!(hit.watchpoint, 0);
+        assert_eq!(hit.pc, 0x0002);
+        assert_eq!(
+            hit.to_string(),
+            "watchpoint 0: STORE ACC, [0x2000] at 0x0002 wrote 8 bits: 0x2 -> 0x1 to [0x2000]"
+        );
+
+        assert_eq!(computer.run(), Ok(StopReason::Watchpoint));
+        let hit = computer.watch_hit().unwrap();
+        assert_eq!(
+            (hit.watchpoint, hit.kind, hit.new),
+            (1, AccessKind::Read, 1)
+        );
+        assert_eq!(computer.run(), Ok(StopReason::Halted));
+        assert_eq!(computer.watch_hit(), None);
+    }
+}